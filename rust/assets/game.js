@@ -0,0 +1,45 @@
+// Commit-reveal helpers for ranked games.
+// A throw is committed as hex(sha256("<throw>:<salt>")) and revealed once both players have committed.
+
+function randomSalt() {
+    const bytes = new Uint8Array(16);
+    crypto.getRandomValues(bytes);
+    return Array.from(bytes, (b) => b.toString(16).padStart(2, "0")).join("");
+}
+
+function throwKey(gameId, round) {
+    return `roshamble:${gameId}:${round}`;
+}
+
+async function commitThrow(gameId, round, move) {
+    const salt = randomSalt();
+    const digest = await crypto.subtle.digest(
+        "SHA-256",
+        new TextEncoder().encode(`${move}:${salt}`),
+    );
+    const commitment = Array.from(new Uint8Array(digest), (b) =>
+        b.toString(16).padStart(2, "0"),
+    ).join("");
+
+    sessionStorage.setItem(
+        throwKey(gameId, round),
+        JSON.stringify({ throw: move, salt }),
+    );
+    htmx.ajax("POST", `/game/${gameId}/commit`, {
+        target: "#game",
+        swap: "outerHTML",
+        values: { commitment },
+    });
+}
+
+function revealThrow(gameId, round) {
+    const saved = sessionStorage.getItem(throwKey(gameId, round));
+    if (!saved) {
+        return;
+    }
+    htmx.ajax("POST", `/game/${gameId}/reveal`, {
+        target: "#game",
+        swap: "outerHTML",
+        values: JSON.parse(saved),
+    });
+}
@@ -0,0 +1,9 @@
+-- Add migration script here
+-- Queue rows reference users, whose ids are UUIDs.
+ALTER TABLE ranked_matchmaking_queue DROP COLUMN player_id;
+ALTER TABLE ranked_matchmaking_queue
+ADD COLUMN player_id UUID UNIQUE NOT NULL REFERENCES users (id) ON DELETE CASCADE;
+
+ALTER TABLE casual_matchmaking_queue DROP COLUMN player_id;
+ALTER TABLE casual_matchmaking_queue
+ADD COLUMN player_id UUID UNIQUE NOT NULL REFERENCES users (id) ON DELETE CASCADE;
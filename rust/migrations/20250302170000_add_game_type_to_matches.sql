@@ -0,0 +1,3 @@
+-- Add migration script here
+ALTER TABLE matchmaking_matches
+ADD COLUMN game_type TEXT NOT NULL DEFAULT 'casual' CHECK (game_type IN ('ranked', 'casual', 'tournament'));
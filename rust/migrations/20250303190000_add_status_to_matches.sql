@@ -0,0 +1,6 @@
+-- Add migration script here
+ALTER TABLE matchmaking_matches
+ADD COLUMN status TEXT NOT NULL DEFAULT 'pending' CHECK (status IN ('pending', 'ready', 'expired'));
+
+CREATE INDEX matchmaking_matches_player1_idx ON matchmaking_matches (player1_id);
+CREATE INDEX matchmaking_matches_player2_idx ON matchmaking_matches (player2_id);
@@ -0,0 +1,4 @@
+-- Add migration script here
+ALTER TABLE users ADD COLUMN last_seen_at TIMESTAMPTZ;
+
+CREATE INDEX users_last_seen_at_idx ON users (last_seen_at);
@@ -0,0 +1,33 @@
+-- Add migration script here
+ALTER TABLE matchmaking_matches DROP CONSTRAINT matchmaking_matches_status_check;
+ALTER TABLE matchmaking_matches
+ADD CONSTRAINT matchmaking_matches_status_check CHECK (status IN ('pending', 'ready', 'expired', 'finished'));
+
+-- A game is the best-of-N series played out for a match
+CREATE TABLE games (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    match_id INT UNIQUE REFERENCES matchmaking_matches (match_id) ON DELETE SET NULL,
+    game_type TEXT NOT NULL CHECK (game_type IN ('ranked', 'casual', 'tournament')),
+    best_of INT NOT NULL CHECK (best_of IN (1, 3, 5)),
+    player1_id UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    player2_id UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    winner_id UUID REFERENCES users (id) ON DELETE SET NULL,
+    status TEXT NOT NULL DEFAULT 'in_progress' CHECK (status IN ('in_progress', 'finished')),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    finished_at TIMESTAMPTZ
+);
+
+CREATE INDEX games_player1_idx ON games (player1_id);
+CREATE INDEX games_player2_idx ON games (player2_id);
+
+-- Every round of a game. A round is resolved once both throws are in.
+CREATE TABLE game_rounds (
+    game_id UUID NOT NULL REFERENCES games (id) ON DELETE CASCADE,
+    round_number INT NOT NULL,
+    player1_throw TEXT CHECK (player1_throw IN ('rock', 'paper', 'scissors')),
+    player2_throw TEXT CHECK (player2_throw IN ('rock', 'paper', 'scissors')),
+    result TEXT CHECK (result IN ('player1', 'player2', 'tie')),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    resolved_at TIMESTAMPTZ,
+    PRIMARY KEY (game_id, round_number)
+);
@@ -0,0 +1,7 @@
+-- Add migration script here
+-- In commit-reveal games each player first submits a salted hash of their throw,
+-- then reveals the throw and salt once both hashes are in.
+ALTER TABLE games ADD COLUMN commit_reveal BOOLEAN NOT NULL DEFAULT FALSE;
+
+ALTER TABLE game_rounds ADD COLUMN player1_commitment TEXT;
+ALTER TABLE game_rounds ADD COLUMN player2_commitment TEXT;
@@ -0,0 +1,9 @@
+-- Add migration script here
+CREATE TABLE ratings (
+    user_id UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    game_type TEXT NOT NULL CHECK (game_type IN ('ranked', 'casual', 'tournament')),
+    rating DOUBLE PRECISION NOT NULL DEFAULT 1000,
+    games_played INT NOT NULL DEFAULT 0,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (user_id, game_type)
+);
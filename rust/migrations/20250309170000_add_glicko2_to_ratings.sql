@@ -0,0 +1,7 @@
+-- Add migration script here
+ALTER TABLE ratings
+    ADD COLUMN deviation DOUBLE PRECISION NOT NULL DEFAULT 350,
+    ADD COLUMN volatility DOUBLE PRECISION NOT NULL DEFAULT 0.06;
+
+ALTER TABLE ranked_matchmaking_queue
+    ADD COLUMN rating_deviation INT NOT NULL DEFAULT 0;
@@ -0,0 +1,7 @@
+-- Add migration script here
+ALTER TABLE matchmaking_matches
+    ADD COLUMN player1_wait_seconds DOUBLE PRECISION,
+    ADD COLUMN player2_wait_seconds DOUBLE PRECISION,
+    ADD COLUMN rating_gap INT;
+
+CREATE INDEX matchmaking_matches_game_type_time_idx ON matchmaking_matches (game_type, match_time);
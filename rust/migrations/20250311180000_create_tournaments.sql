@@ -0,0 +1,39 @@
+-- Add migration script here
+CREATE TABLE tournaments (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    name TEXT NOT NULL,
+    format TEXT NOT NULL DEFAULT 'single_elimination' CHECK (format IN ('single_elimination')),
+    size INT NOT NULL CHECK (size >= 2),
+    status TEXT NOT NULL DEFAULT 'registering' CHECK (status IN ('registering', 'in_progress', 'finished', 'cancelled')),
+    starts_at TIMESTAMPTZ NOT NULL,
+    created_by UUID REFERENCES users (id) ON DELETE SET NULL,
+    winner_id UUID REFERENCES users (id) ON DELETE SET NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    finished_at TIMESTAMPTZ
+);
+
+CREATE INDEX tournaments_status_starts_at_idx ON tournaments (status, starts_at);
+
+-- Seed and rating are filled in when the tournament starts
+CREATE TABLE tournament_players (
+    tournament_id UUID NOT NULL REFERENCES tournaments (id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    seed INT,
+    rating DOUBLE PRECISION,
+    registered_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (tournament_id, user_id)
+);
+
+-- Every slot in a bracket. Later rounds start out empty and fill up as winners advance.
+CREATE TABLE tournament_matches (
+    id SERIAL PRIMARY KEY,
+    tournament_id UUID NOT NULL REFERENCES tournaments (id) ON DELETE CASCADE,
+    round INT NOT NULL,
+    position INT NOT NULL,
+    player1_id UUID REFERENCES users (id) ON DELETE SET NULL,
+    player2_id UUID REFERENCES users (id) ON DELETE SET NULL,
+    match_id INT UNIQUE REFERENCES matchmaking_matches (match_id) ON DELETE SET NULL,
+    winner_id UUID REFERENCES users (id) ON DELETE SET NULL,
+    status TEXT NOT NULL DEFAULT 'waiting' CHECK (status IN ('waiting', 'in_progress', 'finished', 'bye')),
+    UNIQUE (tournament_id, round, position)
+);
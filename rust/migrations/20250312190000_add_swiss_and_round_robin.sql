@@ -0,0 +1,7 @@
+-- Add migration script here
+ALTER TABLE tournaments DROP CONSTRAINT tournaments_format_check;
+ALTER TABLE tournaments
+ADD CONSTRAINT tournaments_format_check CHECK (format IN ('single_elimination', 'swiss', 'round_robin'));
+
+-- Number of rounds the tournament is played over, set when it starts
+ALTER TABLE tournaments ADD COLUMN rounds INT;
@@ -0,0 +1,6 @@
+-- Add migration script here
+-- History is read newest first per player
+DROP INDEX games_player1_idx;
+DROP INDEX games_player2_idx;
+CREATE INDEX games_player1_finished_at_idx ON games (player1_id, finished_at DESC, id DESC);
+CREATE INDEX games_player2_finished_at_idx ON games (player2_id, finished_at DESC, id DESC);
@@ -0,0 +1,21 @@
+-- Add migration script here
+CREATE MATERIALIZED VIEW leaderboard AS
+SELECT
+    r.game_type,
+    r.user_id,
+    u.username,
+    r.rating,
+    r.games_played,
+    RANK() OVER (PARTITION BY r.game_type ORDER BY r.rating DESC) AS rank,
+    ROW_NUMBER() OVER (
+        PARTITION BY r.game_type
+        ORDER BY r.rating DESC, r.games_played DESC, u.username
+    ) AS position,
+    NOW() AS refreshed_at
+FROM ratings r
+JOIN users u ON u.id = r.user_id
+WHERE r.games_played > 0;
+
+-- Needed to refresh the view concurrently
+CREATE UNIQUE INDEX leaderboard_game_type_user_idx ON leaderboard (game_type, user_id);
+CREATE INDEX leaderboard_game_type_position_idx ON leaderboard (game_type, position);
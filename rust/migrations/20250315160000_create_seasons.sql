@@ -0,0 +1,30 @@
+-- Add migration script here
+CREATE TABLE seasons (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL,
+    starts_at TIMESTAMPTZ NOT NULL,
+    ends_at TIMESTAMPTZ NOT NULL CHECK (ends_at > starts_at),
+    status TEXT NOT NULL DEFAULT 'active' CHECK (status IN ('active', 'archived')),
+    archived_at TIMESTAMPTZ
+);
+
+-- Only one season runs at a time
+CREATE UNIQUE INDEX seasons_one_active_idx ON seasons (status) WHERE status = 'active';
+
+-- Final standings of each archived season
+CREATE TABLE season_standings (
+    season_id INT NOT NULL REFERENCES seasons (id) ON DELETE CASCADE,
+    game_type TEXT NOT NULL CHECK (game_type IN ('ranked', 'casual', 'tournament')),
+    user_id UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    rank INT NOT NULL,
+    rating DOUBLE PRECISION NOT NULL,
+    games_played INT NOT NULL,
+    badge TEXT,
+    PRIMARY KEY (season_id, game_type, user_id)
+);
+
+CREATE INDEX season_standings_user_idx ON season_standings (user_id);
+
+-- Rated games played in the current season, so only this season's players are archived
+ALTER TABLE ratings
+    ADD COLUMN season_games INT NOT NULL DEFAULT 0;
@@ -0,0 +1,7 @@
+-- Add migration script here
+-- token holds the SHA-256 of the session token carried in the Authorization cookie
+ALTER TABLE user_sessions
+    ADD COLUMN user_agent TEXT,
+    ADD COLUMN last_used_at TIMESTAMPTZ NOT NULL DEFAULT now();
+
+CREATE INDEX user_sessions_user_idx ON user_sessions (user_id);
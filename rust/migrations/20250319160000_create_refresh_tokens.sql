@@ -0,0 +1,14 @@
+-- Add migration script here
+-- user_sessions.token now holds the hash of the session's current refresh token.
+-- Earlier sessions hashed a different token, so everyone signs in again.
+DELETE FROM user_sessions;
+
+-- Refresh tokens a session has already rotated past. Presenting one again means it
+-- was copied, so the whole session is revoked.
+CREATE TABLE refresh_tokens (
+    token_hash TEXT PRIMARY KEY,
+    session_id UUID NOT NULL REFERENCES user_sessions (id) ON DELETE CASCADE,
+    rotated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX refresh_tokens_session_idx ON refresh_tokens (session_id);
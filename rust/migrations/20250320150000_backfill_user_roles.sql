@@ -0,0 +1,5 @@
+-- Add migration script here
+-- Roles are read at login now, so everyone who signed up before gets the default one
+INSERT INTO user_roles (user_id, role)
+SELECT id, 'user' FROM users
+ON CONFLICT DO NOTHING;
@@ -0,0 +1,19 @@
+-- Add migration script here
+-- Players who sign in through an OIDC provider don't need a password
+ALTER TABLE users ALTER COLUMN password DROP NOT NULL;
+
+-- Passwords stay in users.password, so user_auth only links accounts to providers.
+-- A player can link more than one, and subjects only have to be unique per provider.
+ALTER TABLE user_auth DROP CONSTRAINT user_auth_pkey;
+ALTER TABLE user_auth DROP CONSTRAINT user_auth_provider_id_key;
+ALTER TABLE user_auth ADD PRIMARY KEY (auth_provider, provider_id);
+CREATE INDEX user_auth_user_id_idx ON user_auth (user_id);
+
+-- Sign-ins that were sent to a provider and haven't come back yet
+CREATE TABLE oidc_login_states (
+    state_hash TEXT PRIMARY KEY,
+    provider TEXT NOT NULL,
+    code_verifier TEXT NOT NULL,
+    nonce TEXT NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL
+);
@@ -0,0 +1,3 @@
+-- Add migration script here
+-- Guests play without signing up, so they have no email until they convert to a full account
+ALTER TABLE users ALTER COLUMN email DROP NOT NULL;
@@ -0,0 +1,91 @@
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Response},
+    Form, Json,
+};
+
+use crate::{
+    handlers::wants_json,
+    services::role_service::{self, Role, RoleError, UserRoles},
+    AppState,
+};
+
+#[derive(serde::Deserialize)]
+pub struct RoleForm {
+    role: String,
+}
+
+pub async fn handle_roles(
+    Path(username): Path<String>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let result = role_service::get_user_roles(state.pool.clone(), &username).await;
+    return render_roles(&state, &headers, result);
+}
+
+pub async fn handle_grant_role(
+    Path(username): Path<String>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+    Form(form): Form<RoleForm>,
+) -> impl IntoResponse {
+    let role = match form.role.parse::<Role>() {
+        Ok(role) => role,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    let result = role_service::grant_role(state.pool.clone(), &username, role).await;
+    return render_roles(&state, &headers, result);
+}
+
+pub async fn handle_revoke_role(
+    Path((username, role)): Path<(String, String)>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let role = match role.parse::<Role>() {
+        Ok(role) => role,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    let result = role_service::revoke_role(state.pool.clone(), &username, role).await;
+    return render_roles(&state, &headers, result);
+}
+
+fn render_roles(
+    state: &AppState,
+    headers: &HeaderMap,
+    result: Result<UserRoles, RoleError>,
+) -> Response {
+    match result {
+        Ok(user_roles) => {
+            if wants_json(headers) {
+                return Json(user_roles).into_response();
+            }
+            let data = serde_json::json!({
+                "username": user_roles.username,
+                "roles": user_roles.roles,
+                "all_roles": [Role::Admin, Role::Moderator, Role::User, Role::Guest],
+            });
+            let body = state.templates.render("admin/user_roles", &data).unwrap();
+            return Html(body).into_response();
+        }
+        Err(e) => return role_error_response(e),
+    }
+}
+
+fn role_error_response(e: RoleError) -> Response {
+    let status = match e {
+        RoleError::UserNotFound => StatusCode::NOT_FOUND,
+        RoleError::LastAdmin => StatusCode::CONFLICT,
+        RoleError::Database(ref db_err) => {
+            tracing::error!("{:?}", db_err);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "There was an error updating roles. Please try again later...",
+            )
+                .into_response();
+        }
+    };
+    return (status, e.to_string()).into_response();
+}
@@ -1,12 +1,21 @@
 use axum::{
-    extract::State,
+    extract::{ConnectInfo, Path, Query, State},
     http::{header, HeaderMap, HeaderValue, StatusCode},
-    response::{Html, IntoResponse},
-    Form,
+    response::{Html, IntoResponse, Response},
+    Extension, Form,
 };
+use std::net::SocketAddr;
+use uuid::Uuid;
 
 use crate::{
-    services::users_service::{self, LoginRequest, LoginResponse, NewUserRequest, SignUpResponse},
+    services::{
+        email_verification_service,
+        guest_service::{self, ConvertGuestForm, GuestError},
+        mail_service, oidc_service,
+        password_reset_service::{self, NewPasswordForm, PasswordResetError, ResetRequestForm},
+        role_service, session_service,
+        users_service::{self, Claims, LoginRequest, NewUserRequest},
+    },
     AppState,
 };
 
@@ -16,10 +25,7 @@ pub async fn get_sign_up(State(state): State<AppState>) -> impl IntoResponse {
             .templates
             .render(
                 "auth/register",
-                &SignUpResponse {
-                    message: "".to_string(),
-                    token: Some("".to_string()),
-                },
+                &serde_json::json!({ "providers": oidc_service::provider_links() }),
             )
             .unwrap()
             .into_response(),
@@ -28,20 +34,21 @@ pub async fn get_sign_up(State(state): State<AppState>) -> impl IntoResponse {
 
 pub async fn sign_up(
     State(state): State<AppState>,
+    request_headers: HeaderMap,
     Form(form): Form<NewUserRequest>,
 ) -> impl IntoResponse {
-    let response = users_service::sign_up_user(state.pool, form).await;
+    let email = form.email.clone();
+    let response =
+        users_service::sign_up_user(state.pool.clone(), form, user_agent(&request_headers)).await;
 
     let mut headers = HeaderMap::new();
-    if response.0.is_success() && response.1.token.is_some() {
-        headers.insert(
-            header::SET_COOKIE,
-            HeaderValue::from_str(&format!(
-                "Authorization={}; HttpOnly; Secure; Path=/; SameSite=Strict",
-                response.1.token.unwrap_or("".to_string())
-            ))
-            .unwrap(),
-        );
+    if let (true, Some(token), Some(refresh_token)) = (
+        response.0.is_success(),
+        &response.1.token,
+        &response.1.refresh_token,
+    ) {
+        send_verification_email(&state, &email).await;
+        set_session_cookies(&mut headers, token, Some(refresh_token));
         headers.insert("HX-Redirect", "/dashboard".parse().unwrap());
         return (StatusCode::OK, headers, "").into_response();
     } else if response.0.is_client_error() {
@@ -51,16 +58,153 @@ pub async fn sign_up(
     }
 }
 
+// Starts a session as a new guest, who can play casual games without signing up
+pub async fn play_as_guest(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request_headers: HeaderMap,
+) -> impl IntoResponse {
+    let address = state.guests.client_address(peer.ip(), &request_headers);
+    if !state.guests.try_acquire(address) {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            "Too many guest accounts were made from your network. Please try again later...",
+        )
+            .into_response();
+    }
+    let session = match guest_service::create_guest(state.pool.clone()).await {
+        Ok(user_id) => {
+            users_service::start_session(&state.pool, user_id, user_agent(&request_headers)).await
+        }
+        Err(e) => {
+            tracing::error!("error creating guest {}", e.to_string());
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "There was an error. Please try again later...",
+            )
+                .into_response();
+        }
+    };
+    match session {
+        Ok((token, session)) => {
+            let mut headers = HeaderMap::new();
+            set_session_cookies(&mut headers, &token, Some(&session.refresh_token));
+            headers.insert("HX-Redirect", "/dashboard".parse().unwrap());
+            return (StatusCode::OK, headers, "").into_response();
+        }
+        Err(e) => {
+            tracing::error!("error starting session {}", e.to_string());
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "There was an error. Please try again later...",
+            )
+                .into_response();
+        }
+    }
+}
+
+pub async fn get_convert_guest(State(state): State<AppState>) -> impl IntoResponse {
+    return Html(
+        state
+            .templates
+            .render("auth/convert_guest", &serde_json::json!({}))
+            .unwrap(),
+    );
+}
+
+// Turns the guest into a full account, keeping their history
+pub async fn convert_guest(
+    Extension(player): Extension<Claims>,
+    State(state): State<AppState>,
+    Form(form): Form<ConvertGuestForm>,
+) -> impl IntoResponse {
+    let (Ok(user_id), Ok(session_id)) = (
+        Uuid::parse_str(&player.id),
+        Uuid::parse_str(&player.session),
+    ) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    let email = form.email.clone();
+    if let Err(e) = guest_service::convert_guest(state.pool.clone(), user_id, form).await {
+        let status = match e {
+            GuestError::NotAGuest | GuestError::AlreadyExists => StatusCode::CONFLICT,
+            GuestError::InvalidUsername
+            | GuestError::InvalidEmail
+            | GuestError::PasswordTooShort => StatusCode::BAD_REQUEST,
+            GuestError::Database(ref db_err) => {
+                tracing::error!("{:?}", db_err);
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "There was an error creating your account. Please try again later...",
+                )
+                    .into_response();
+            }
+        };
+        return (status, e.to_string()).into_response();
+    }
+    send_verification_email(&state, &email).await;
+
+    // The access token still says they're a guest
+    let mut headers = HeaderMap::new();
+    match users_service::issue_access_token(&state.pool, user_id, session_id).await {
+        Ok(token) => set_session_cookies(&mut headers, &token, None),
+        Err(e) => tracing::error!("error issuing access token {}", e.to_string()),
+    }
+    headers.insert("HX-Redirect", "/dashboard".parse().unwrap());
+    return (StatusCode::OK, headers, "").into_response();
+}
+
+pub fn user_agent(headers: &HeaderMap) -> Option<&str> {
+    return headers
+        .get(header::USER_AGENT)
+        .and_then(|agent| agent.to_str().ok());
+}
+
+// Adds the cookies a signed in browser carries: the short lived access token, and the
+// refresh token auth_middleware swaps for a new one once it expires. The refresh cookie
+// is left alone when only the access token changed.
+pub fn set_session_cookies(headers: &mut HeaderMap, token: &str, refresh_token: Option<&str>) {
+    headers.append(
+        header::SET_COOKIE,
+        HeaderValue::from_str(&format!(
+            "Authorization={}; HttpOnly; Secure; Path=/; SameSite=Strict",
+            token
+        ))
+        .unwrap(),
+    );
+    if let Some(refresh_token) = refresh_token {
+        headers.append(
+            header::SET_COOKIE,
+            HeaderValue::from_str(&format!(
+                "Refresh={}; HttpOnly; Secure; Path=/; SameSite=Strict; Max-Age={}",
+                refresh_token,
+                session_service::session_seconds()
+            ))
+            .unwrap(),
+        );
+    }
+}
+
+pub fn clear_session_cookies(headers: &mut HeaderMap) {
+    for cookie in ["Authorization", "Refresh"] {
+        headers.append(
+            header::SET_COOKIE,
+            HeaderValue::from_str(&format!(
+                "{}=; HttpOnly; Secure; Path=/; SameSite=Strict; Max-Age=0",
+                cookie
+            ))
+            .unwrap(),
+        );
+    }
+}
+
 pub async fn get_log_in(State(state): State<AppState>) -> impl IntoResponse {
     return Html(
         state
             .templates
             .render(
                 "auth/login",
-                &LoginResponse {
-                    message: "".to_string(),
-                    token: "".to_string(),
-                },
+                &serde_json::json!({ "providers": oidc_service::provider_links() }),
             )
             .unwrap()
             .into_response(),
@@ -69,19 +213,17 @@ pub async fn get_log_in(State(state): State<AppState>) -> impl IntoResponse {
 
 pub async fn log_in(
     State(state): State<AppState>,
+    request_headers: HeaderMap,
     Form(form): Form<LoginRequest>,
 ) -> impl IntoResponse {
-    let response = users_service::log_in_user(state.pool, form).await;
+    let response = users_service::log_in_user(state.pool, form, user_agent(&request_headers)).await;
 
     let mut headers = HeaderMap::new();
     if response.0.is_success() {
-        headers.insert(
-            header::SET_COOKIE,
-            HeaderValue::from_str(&format!(
-                "Authorization={}; HttpOnly; Secure; Path=/; SameSite=Strict",
-                response.1.token
-            ))
-            .unwrap(),
+        set_session_cookies(
+            &mut headers,
+            &response.1.token,
+            Some(&response.1.refresh_token),
         );
         headers.insert("HX-Redirect", "/dashboard".parse().unwrap());
         return (StatusCode::OK, headers, "").into_response();
@@ -92,7 +234,36 @@ pub async fn log_in(
     }
 }
 
-pub async fn password_reset(State(state): State<AppState>) -> impl IntoResponse {
+// Always answers the same way whether or not the email has an account,
+// so the form can't be used to find out who has one
+pub async fn password_reset(
+    State(state): State<AppState>,
+    Form(form): Form<ResetRequestForm>,
+) -> impl IntoResponse {
+    match password_reset_service::create_reset_token(state.pool, form.email.trim()).await {
+        Ok(Some(reset)) => {
+            mail_service::send_in_background(
+                state.mailer.clone(),
+                password_reset_service::reset_email(&state.templates, &reset),
+            );
+        }
+        Ok(None) => {}
+        Err(e) => {
+            tracing::error!("error creating password reset token {}", e.to_string());
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "There was an error. Please try again later...",
+            )
+                .into_response();
+        }
+    }
+    return Html(
+        "If an account uses that email, we've sent it a link to reset the password.".to_string(),
+    )
+    .into_response();
+}
+
+pub async fn get_password_reset(State(state): State<AppState>) -> impl IntoResponse {
     return Html(
         state
             .templates
@@ -102,12 +273,242 @@ pub async fn password_reset(State(state): State<AppState>) -> impl IntoResponse
     );
 }
 
-pub async fn get_password_reset(State(state): State<AppState>) -> impl IntoResponse {
+#[derive(serde::Deserialize)]
+pub struct ResetLinkQuery {
+    token: String,
+}
+
+// The page the emailed link opens, so it's a whole page rather than a fragment
+pub async fn get_password_reset_confirm(
+    State(state): State<AppState>,
+    Query(query): Query<ResetLinkQuery>,
+) -> impl IntoResponse {
+    let valid = match password_reset_service::is_valid_token(state.pool, &query.token).await {
+        Ok(valid) => valid,
+        Err(e) => {
+            tracing::error!("error checking password reset token {}", e.to_string());
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "There was an error. Please try again later...",
+            )
+                .into_response();
+        }
+    };
     return Html(
         state
             .templates
-            .render("auth/password_reset", &serde_json::json!({}))
-            .unwrap()
-            .into_response(),
-    );
+            .render(
+                "auth/password_reset_confirm",
+                &serde_json::json!({ "token": query.token, "valid": valid }),
+            )
+            .unwrap(),
+    )
+    .into_response();
+}
+
+pub async fn password_reset_confirm(
+    State(state): State<AppState>,
+    Form(form): Form<NewPasswordForm>,
+) -> impl IntoResponse {
+    match password_reset_service::reset_password(state.pool.clone(), form).await {
+        Ok(user_id) => {
+            // Whoever had the old password may still hold a session, so sign every device out
+            if let Err(e) =
+                session_service::revoke_all_sessions(&state.pool, &state.sessions, user_id).await
+            {
+                tracing::error!(
+                    "error ending sessions after password reset {}",
+                    e.to_string()
+                );
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Your password was changed but we couldn't sign out your other devices. Please try again later...",
+                )
+                    .into_response();
+            }
+            let mut headers = HeaderMap::new();
+            headers.insert("HX-Redirect", "/".parse().unwrap());
+            return (StatusCode::OK, headers, "").into_response();
+        }
+        Err(PasswordResetError::Database(e)) => {
+            tracing::error!("error resetting password {}", e.to_string());
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "There was an error resetting your password. Please try again later...",
+            )
+                .into_response();
+        }
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    }
+}
+
+// Emails a verification link unless the account is already verified. Failing to send
+// isn't fatal, players can ask for another link later.
+async fn send_verification_email(state: &AppState, email: &str) -> bool {
+    match email_verification_service::create_verification_token(state.pool.clone(), email).await {
+        Ok(Some(verification)) => {
+            mail_service::send_in_background(
+                state.mailer.clone(),
+                email_verification_service::verification_email(&state.templates, &verification),
+            );
+            return true;
+        }
+        Ok(None) => return false,
+        Err(e) => {
+            tracing::error!("error creating email verification token {}", e.to_string());
+            return false;
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct VerifyEmailQuery {
+    token: String,
+}
+
+// The page the emailed link opens, so it's a whole page rather than a fragment
+pub async fn verify_email(
+    State(state): State<AppState>,
+    Query(query): Query<VerifyEmailQuery>,
+) -> impl IntoResponse {
+    let username =
+        match email_verification_service::verify_email(state.pool.clone(), &query.token).await {
+            Ok(username) => username,
+            Err(e) => {
+                tracing::error!("error verifying email {}", e.to_string());
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "There was an error verifying your email. Please try again later...",
+                )
+                    .into_response();
+            }
+        };
+    if username.is_some() {
+        if let Err(e) = role_service::grant_admins_from_env(&state.pool).await {
+            tracing::error!("error granting admin roles {}", e.to_string());
+        }
+    }
+    return Html(
+        state
+            .templates
+            .render(
+                "auth/verify_email",
+                &serde_json::json!({ "verified": username.is_some(), "username": username }),
+            )
+            .unwrap(),
+    )
+    .into_response();
+}
+
+pub async fn resend_verification_email(
+    Extension(player): Extension<Claims>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    if send_verification_email(&state, &player.email).await {
+        return Html("We've sent you a new verification link.");
+    }
+    return Html("Your email is already verified.");
+}
+
+pub async fn log_out(
+    Extension(player): Extension<Claims>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let (Ok(user_id), Ok(session_id)) = (
+        Uuid::parse_str(&player.id),
+        Uuid::parse_str(&player.session),
+    ) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    if let Err(e) =
+        session_service::revoke_session(&state.pool, &state.sessions, user_id, session_id).await
+    {
+        tracing::error!("error ending session {}", e.to_string());
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "There was an error logging out. Please try again later...",
+        )
+            .into_response();
+    }
+    return logged_out_response();
+}
+
+pub async fn log_out_everywhere(
+    Extension(player): Extension<Claims>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let Ok(user_id) = Uuid::parse_str(&player.id) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    if let Err(e) =
+        session_service::revoke_all_sessions(&state.pool, &state.sessions, user_id).await
+    {
+        tracing::error!("error ending sessions {}", e.to_string());
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "There was an error logging out. Please try again later...",
+        )
+            .into_response();
+    }
+    return logged_out_response();
+}
+
+// Clears the cookies and sends the browser back to the landing page
+fn logged_out_response() -> Response {
+    let mut headers = HeaderMap::new();
+    clear_session_cookies(&mut headers);
+    headers.insert("HX-Redirect", "/".parse().unwrap());
+    return (StatusCode::OK, headers, "").into_response();
+}
+
+pub async fn get_sessions(
+    Extension(player): Extension<Claims>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let (Ok(user_id), Ok(session_id)) = (
+        Uuid::parse_str(&player.id),
+        Uuid::parse_str(&player.session),
+    ) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    match session_service::list_sessions(&state.pool, user_id, session_id).await {
+        Ok(sessions) => {
+            let body = state
+                .templates
+                .render("sessions", &serde_json::json!({ "sessions": sessions }))
+                .unwrap();
+            return Html(body).into_response();
+        }
+        Err(e) => {
+            tracing::error!("error listing sessions {}", e.to_string());
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "There was an error loading your sessions. Please try again later...",
+            )
+                .into_response();
+        }
+    }
+}
+
+pub async fn revoke_session(
+    Path(session_id): Path<Uuid>,
+    Extension(player): Extension<Claims>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let Ok(user_id) = Uuid::parse_str(&player.id) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    match session_service::revoke_session(&state.pool, &state.sessions, user_id, session_id).await {
+        // The row is swapped out for nothing
+        Ok(true) => return Html("").into_response(),
+        Ok(false) => return (StatusCode::NOT_FOUND, "Session not found").into_response(),
+        Err(e) => {
+            tracing::error!("error revoking session {}", e.to_string());
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "There was an error revoking the session. Please try again later...",
+            )
+                .into_response();
+        }
+    }
 }
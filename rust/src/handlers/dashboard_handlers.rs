@@ -5,10 +5,16 @@ use axum::{
 };
 use serde_json::json;
 
-use crate::{services::users_service::Claims, AppState};
+use crate::{
+    services::{role_service::Role, users_service::Claims},
+    AppState,
+};
 
-pub async fn handle_dashboard(State(state): State<AppState>) -> impl IntoResponse {
-    let data = json!({});
+pub async fn handle_dashboard(
+    Extension(claims): Extension<Claims>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let data = json!({ "is_guest": claims.has_role(Role::Guest) });
     let body = state.templates.render("dashboard", &data).unwrap();
     Html(body)
 }
@@ -20,7 +26,8 @@ pub async fn handle_gametypes(
     let data = json!({
         "player": {
             "id": claims.id,
-        }
+        },
+        "is_guest": claims.has_role(Role::Guest),
     });
     let body = state.templates.render("gametypes", &data).unwrap();
     Html(body)
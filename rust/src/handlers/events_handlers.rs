@@ -0,0 +1,153 @@
+use std::{convert::Infallible, time::Duration};
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    http::StatusCode,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
+    Extension,
+};
+use tokio::sync::{broadcast::error::RecvError, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use uuid::Uuid;
+
+use crate::{
+    services::{session_service, users_service::Claims},
+    AppState,
+};
+
+// How often an open stream checks its session hasn't been ended, e.g. by logging out everywhere
+const SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+// Streams matchmaking and game events for the logged in player over a WebSocket.
+// See `events_service::GameEvent` for the message schema. The channel is server to client only,
+// anything the client sends besides close frames is ignored.
+pub async fn handle_ws(
+    ws: WebSocketUpgrade,
+    Extension(player): Extension<Claims>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let (Ok(player_id), Ok(session_id)) = (
+        Uuid::parse_str(&player.id),
+        Uuid::parse_str(&player.session),
+    ) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    return ws
+        .on_upgrade(move |socket| stream_events(socket, state, player_id, session_id))
+        .into_response();
+}
+
+async fn stream_events(mut socket: WebSocket, state: AppState, player_id: Uuid, session_id: Uuid) {
+    let mut events = state.events.subscribe();
+    let mut session_check = tokio::time::interval(SESSION_CHECK_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = session_check.tick() => {
+                if !session_is_active(&state, session_id).await {
+                    let _ = socket.send(Message::Close(None)).await;
+                    return;
+                }
+            }
+            event = events.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!("websocket for {} skipped {} events", player_id, skipped);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return,
+                };
+                if !event.is_for(player_id) {
+                    continue;
+                }
+                let payload = serde_json::to_string(&event).unwrap();
+                if socket.send(Message::Text(payload.into())).await.is_err() {
+                    return;
+                }
+            }
+            message = socket.recv() => {
+                match message {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                    Some(Ok(_)) => {}
+                }
+            }
+        }
+    }
+}
+
+// Server-Sent Events fallback for clients that can't hold a WebSocket open.
+// Carries the same JSON messages as the WebSocket, with the SSE event name set to the message "type"
+// so the htmx SSE extension can trigger on them (e.g. hx-trigger="sse:match_found").
+pub async fn handle_sse(
+    Extension(player): Extension<Claims>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let (Ok(player_id), Ok(session_id)) = (
+        Uuid::parse_str(&player.id),
+        Uuid::parse_str(&player.session),
+    ) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    // The stream ends when the forwarding task drops its sender
+    let (sender, receiver) = mpsc::channel(16);
+    tokio::spawn(forward_events(state, player_id, session_id, sender));
+
+    return Sse::new(ReceiverStream::new(receiver))
+        .keep_alive(KeepAlive::default())
+        .into_response();
+}
+
+async fn forward_events(
+    state: AppState,
+    player_id: Uuid,
+    session_id: Uuid,
+    sender: mpsc::Sender<Result<Event, Infallible>>,
+) {
+    let mut events = state.events.subscribe();
+    let mut session_check = tokio::time::interval(SESSION_CHECK_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = session_check.tick() => {
+                if !session_is_active(&state, session_id).await {
+                    return;
+                }
+            }
+            event = events.recv() => {
+                let event = match event {
+                    Ok(event) => event,
+                    // Lagged subscribers just miss the skipped events
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return,
+                };
+                if !event.is_for(player_id) {
+                    continue;
+                }
+                let payload = serde_json::to_string(&event).unwrap();
+                let message = Event::default().event(event.name()).data(payload);
+                if sender.send(Ok(message)).await.is_err() {
+                    return;
+                }
+            }
+            // The client went away
+            _ = sender.closed() => return,
+        }
+    }
+}
+
+// Whether a stream's session is still going. Errors end the stream too, the client can reconnect.
+async fn session_is_active(state: &AppState, session_id: Uuid) -> bool {
+    match session_service::check_session(&state.pool, &state.sessions, session_id).await {
+        Ok(active) => return active,
+        Err(e) => {
+            tracing::error!("error checking session {}", e.to_string());
+            return false;
+        }
+    }
+}
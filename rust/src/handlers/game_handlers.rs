@@ -0,0 +1,132 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+    Extension, Form,
+};
+use uuid::Uuid;
+
+use crate::{
+    services::{
+        game_service::{self, GameError, Throw},
+        users_service::Claims,
+    },
+    AppState,
+};
+
+#[derive(serde::Deserialize)]
+pub struct ThrowRequest {
+    throw: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct CommitRequest {
+    commitment: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct RevealRequest {
+    throw: String,
+    salt: String,
+}
+
+pub async fn handle_game(
+    Path(game_id): Path<Uuid>,
+    Extension(player): Extension<Claims>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    return render_game(&state, game_id, &player).await;
+}
+
+pub async fn handle_throw(
+    Path(game_id): Path<Uuid>,
+    Extension(player): Extension<Claims>,
+    State(state): State<AppState>,
+    Form(form): Form<ThrowRequest>,
+) -> impl IntoResponse {
+    let throw = match form.throw.parse::<Throw>() {
+        Ok(throw) => throw,
+        Err(e) => return game_error_response(e),
+    };
+    let Ok(player_id) = Uuid::parse_str(&player.id) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    if let Err(e) = game_service::submit_throw(state.pool.clone(), game_id, player_id, throw).await
+    {
+        return game_error_response(e);
+    }
+    return render_game(&state, game_id, &player).await;
+}
+
+pub async fn handle_commit(
+    Path(game_id): Path<Uuid>,
+    Extension(player): Extension<Claims>,
+    State(state): State<AppState>,
+    Form(form): Form<CommitRequest>,
+) -> impl IntoResponse {
+    let Ok(player_id) = Uuid::parse_str(&player.id) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    if let Err(e) =
+        game_service::submit_commitment(state.pool.clone(), game_id, player_id, form.commitment)
+            .await
+    {
+        return game_error_response(e);
+    }
+    return render_game(&state, game_id, &player).await;
+}
+
+pub async fn handle_reveal(
+    Path(game_id): Path<Uuid>,
+    Extension(player): Extension<Claims>,
+    State(state): State<AppState>,
+    Form(form): Form<RevealRequest>,
+) -> impl IntoResponse {
+    let throw = match form.throw.parse::<Throw>() {
+        Ok(throw) => throw,
+        Err(e) => return game_error_response(e),
+    };
+    let Ok(player_id) = Uuid::parse_str(&player.id) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    if let Err(e) =
+        game_service::reveal_throw(state.pool.clone(), game_id, player_id, throw, form.salt).await
+    {
+        return game_error_response(e);
+    }
+    return render_game(&state, game_id, &player).await;
+}
+
+// Renders the game screen from the point of view of the given player
+pub async fn render_game(state: &AppState, game_id: Uuid, player: &Claims) -> Response {
+    let Ok(player_id) = Uuid::parse_str(&player.id) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    match game_service::get_game_view(state.pool.clone(), game_id, player_id).await {
+        Ok(game) => {
+            let body = state.templates.render("game", &game).unwrap();
+            return Html(body).into_response();
+        }
+        Err(e) => return game_error_response(e),
+    }
+}
+
+fn game_error_response(e: GameError) -> Response {
+    let status = match e {
+        GameError::NotFound => StatusCode::NOT_FOUND,
+        GameError::NotAPlayer => StatusCode::FORBIDDEN,
+        GameError::Database(ref db_err) => {
+            tracing::error!("{:?}", db_err);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "There was an error. Please try again later...",
+            )
+                .into_response();
+        }
+        _ => StatusCode::BAD_REQUEST,
+    };
+    return (status, e.to_string()).into_response();
+}
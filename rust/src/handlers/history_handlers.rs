@@ -0,0 +1,81 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Response},
+    Extension, Json,
+};
+use serde_json::json;
+
+use crate::{
+    handlers::wants_json,
+    services::{
+        history_service::{self, HistoryError, HistoryFilter},
+        users_service::Claims,
+    },
+    AppState,
+};
+
+pub async fn handle_own_history(
+    Extension(player): Extension<Claims>,
+    Query(filter): Query<HistoryFilter>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    return render_history(&state, &player.username, filter, &headers).await;
+}
+
+pub async fn handle_history(
+    Path(username): Path<String>,
+    Query(filter): Query<HistoryFilter>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    return render_history(&state, &username, filter, &headers).await;
+}
+
+// JSON when asked for with an Accept header. Otherwise the whole history page, or just
+// the table rows when HTMX is filtering or scrolling for more.
+async fn render_history(
+    state: &AppState,
+    username: &str,
+    filter: HistoryFilter,
+    headers: &HeaderMap,
+) -> Response {
+    let page = match history_service::get_history(state.pool.clone(), username, &filter).await {
+        Ok(page) => page,
+        Err(e) => return history_error_response(e),
+    };
+    if wants_json(headers) {
+        return Json(page).into_response();
+    }
+
+    let data = json!({
+        "username": page.username,
+        "games": page.games,
+        "next_cursor": page.next_cursor,
+        "base_url": format!("/history/{}", page.username),
+        "filter": filter,
+    });
+    let rows_only = headers
+        .get("HX-Target")
+        .is_some_and(|target| target == "history-rows" || target == "history-more");
+    let template = if rows_only { "history_rows" } else { "history" };
+    let body = state.templates.render(template, &data).unwrap();
+    return Html(body).into_response();
+}
+
+fn history_error_response(e: HistoryError) -> Response {
+    let status = match e {
+        HistoryError::NotFound => StatusCode::NOT_FOUND,
+        HistoryError::InvalidFilter(_) => StatusCode::BAD_REQUEST,
+        HistoryError::Database(ref db_err) => {
+            tracing::error!("{:?}", db_err);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "There was an error. Please try again later...",
+            )
+                .into_response();
+        }
+    };
+    return (status, e.to_string()).into_response();
+}
@@ -0,0 +1,72 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Response},
+    Extension, Json,
+};
+use uuid::Uuid;
+
+use crate::{
+    handlers::wants_json,
+    services::{leaderboard_service, matchmaking_service::GameType, users_service::Claims},
+    AppState,
+};
+
+#[derive(serde::Deserialize)]
+pub struct LeaderboardQuery {
+    limit: Option<i64>,
+}
+
+// The ranked ladder is the one shown when no mode is picked
+pub async fn handle_global_leaderboard(
+    Extension(player): Extension<Claims>,
+    Query(query): Query<LeaderboardQuery>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    return render_leaderboard(&state, GameType::Ranked, &player, query.limit, &headers).await;
+}
+
+pub async fn handle_leaderboard(
+    Path(gamemode): Path<String>,
+    Extension(player): Extension<Claims>,
+    Query(query): Query<LeaderboardQuery>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let gamemode = match gamemode.parse::<GameType>() {
+        Ok(gamemode) => gamemode,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+    return render_leaderboard(&state, gamemode, &player, query.limit, &headers).await;
+}
+
+async fn render_leaderboard(
+    state: &AppState,
+    gamemode: GameType,
+    viewer: &Claims,
+    limit: Option<i64>,
+    headers: &HeaderMap,
+) -> Response {
+    let Ok(viewer_id) = Uuid::parse_str(&viewer.id) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    match leaderboard_service::get_leaderboard(state.pool.clone(), gamemode, viewer_id, limit).await
+    {
+        Ok(leaderboard) => {
+            if wants_json(headers) {
+                return Json(leaderboard).into_response();
+            }
+            let body = state.templates.render("leaderboard", &leaderboard).unwrap();
+            return Html(body).into_response();
+        }
+        Err(e) => {
+            tracing::error!("error loading leaderboard {}", e.to_string());
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "There was an error loading the leaderboard. Please try again later...",
+            )
+                .into_response();
+        }
+    }
+}
@@ -1,14 +1,18 @@
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
     response::{Html, IntoResponse},
-    Extension,
+    Extension, Json,
 };
 use serde_json::json;
+use uuid::Uuid;
 
 use crate::{
+    handlers::game_handlers,
     services::{
-        matchmaking_service::{self, GameType},
-        users_service::Claims,
+        email_verification_service,
+        matchmaking_service::{self, GameType, MatchStatus},
+        users_service::{self, Claims},
     },
     AppState,
 };
@@ -24,9 +28,39 @@ pub async fn handle_ranked(
             "id": player_id
         }
     });
-    matchmaking_service::add_player_to_ranked_queue(state.pool, player, GameType::Ranked).await;
+    if email_verification_service::ranked_requires_verified_email() {
+        let Ok(user_id) = Uuid::parse_str(&player.id) else {
+            return StatusCode::UNAUTHORIZED.into_response();
+        };
+        match email_verification_service::is_verified(state.pool.clone(), user_id).await {
+            Ok(true) => {}
+            Ok(false) => {
+                let body = state
+                    .templates
+                    .render("verify_email_required", &json!({}))
+                    .unwrap();
+                return Html(body).into_response();
+            }
+            Err(e) => {
+                tracing::error!("error checking email verification {}", e.to_string());
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "There was an error joining the queue. Please try again later...",
+                )
+                    .into_response();
+            }
+        }
+    }
+    if let Err(e) = matchmaking_service::add_player_to_ranked_queue(state.pool, player).await {
+        tracing::error!("error adding player to ranked queue {}", e.to_string());
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "There was an error joining the queue. Please try again later...",
+        )
+            .into_response();
+    }
     let body = state.templates.render("matchmaking", &data).unwrap();
-    return Html(body);
+    return Html(body).into_response();
 }
 
 pub async fn handle_casual(
@@ -40,19 +74,116 @@ pub async fn handle_casual(
                     "id": player_id
                 }
     });
-    matchmaking_service::add_player_to_casual_queue(state.pool, player, GameType::Casual).await;
+    if let Err(e) = matchmaking_service::add_player_to_casual_queue(state.pool, player).await {
+        tracing::error!("error adding player to casual queue {}", e.to_string());
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "There was an error joining the queue. Please try again later...",
+        )
+            .into_response();
+    }
     let body = state.templates.render("matchmaking", &data).unwrap();
-    return Html(body);
+    return Html(body).into_response();
 }
 
 pub async fn handle_ready(
-    Path(player_id): Path<String>,
+    Extension(player): Extension<Claims>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let status =
+        match matchmaking_service::check_player_match(state.pool.clone(), player.clone()).await {
+            Ok(status) => status,
+            Err(e) => {
+                tracing::error!("error checking player match {}", e.to_string());
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "There was an error finding your match. Please try again later...",
+                )
+                    .into_response();
+            }
+        };
+
+    match status {
+        MatchStatus::Searching => return Html("Waiting for a match...").into_response(),
+        MatchStatus::MatchFound { opponent, .. } => {
+            return Html(format!(
+                "Match found against {}! Waiting for them to get ready...",
+                opponent
+            ))
+            .into_response();
+        }
+        MatchStatus::BothReady { game_id } => {
+            // Swap the whole page over to the game screen, which also stops the polling
+            let mut headers = HeaderMap::new();
+            headers.insert("HX-Retarget", "#main".parse().unwrap());
+            headers.insert("HX-Reswap", "innerHTML".parse().unwrap());
+            return (
+                headers,
+                game_handlers::render_game(&state, game_id, &player).await,
+            )
+                .into_response();
+        }
+        MatchStatus::Expired => {
+            let body = state.templates.render("match_expired", &json!({})).unwrap();
+            return Html(body).into_response();
+        }
+    }
+}
+
+pub async fn handle_count(
+    Path(gamemode): Path<String>,
     State(state): State<AppState>,
 ) -> impl IntoResponse {
-    matchmaking_service::check_player_match(player_id).await;
-    return Html("Waiting for a match...");
+    let gamemode = match gamemode.parse::<GameType>() {
+        Ok(gamemode) => gamemode,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+
+    let queued = matchmaking_service::count_queued_players(&state.pool, gamemode).await;
+    let online = users_service::count_online_users(state.pool).await;
+    match (queued, online) {
+        (Ok(queued), Ok(online)) => {
+            return Html(format!(
+                "{} players in queue · {} players online",
+                queued, online
+            ))
+            .into_response();
+        }
+        (Err(e), _) | (_, Err(e)) => {
+            tracing::error!("error counting players {}", e.to_string());
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "There was an error counting players. Please try again later...",
+            )
+                .into_response();
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct MetricsQuery {
+    hours: Option<f64>,
 }
 
-pub async fn handle_count(State(state): State<AppState>) -> impl IntoResponse {
-    return Html("14 players online");
+pub async fn handle_metrics(
+    Path(gamemode): Path<String>,
+    Query(query): Query<MetricsQuery>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let gamemode = match gamemode.parse::<GameType>() {
+        Ok(gamemode) => gamemode,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+
+    match matchmaking_service::get_match_metrics(state.pool, gamemode, query.hours).await {
+        Ok(metrics) => return Json(metrics).into_response(),
+        Err(e) => {
+            tracing::error!("error getting match metrics {}", e.to_string());
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "There was an error getting match metrics. Please try again later...",
+            )
+                .into_response();
+        }
+    }
 }
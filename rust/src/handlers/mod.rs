@@ -1,3 +1,22 @@
+pub mod admin_handlers;
 pub mod auth_handlers;
 pub mod dashboard_handlers;
+pub mod events_handlers;
+pub mod game_handlers;
+pub mod history_handlers;
+pub mod leaderboard_handlers;
 pub mod matchmaking_handlers;
+pub mod oidc_handlers;
+pub mod profile_handlers;
+pub mod season_handlers;
+pub mod tournament_handlers;
+
+use axum::http::{header::ACCEPT, HeaderMap};
+
+// Whether the client asked for JSON rather than an HTML fragment
+pub fn wants_json(headers: &HeaderMap) -> bool {
+    return headers
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json"));
+}
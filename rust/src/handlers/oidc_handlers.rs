@@ -0,0 +1,146 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::CookieJar;
+use uuid::Uuid;
+
+use crate::{
+    handlers::auth_handlers,
+    services::{
+        oidc_service::{self, OidcError},
+        users_service,
+    },
+    AppState,
+};
+
+// Ties a sign-in to the browser that started it. Lax, since the provider sends the
+// player back with a cross-site redirect.
+const STATE_COOKIE: &str = "OidcState";
+
+#[derive(serde::Deserialize)]
+pub struct OidcCallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+// Sends the player to the provider to sign in
+pub async fn oidc_login(
+    Path(provider): Path<String>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let provider = match oidc_service::provider(&provider) {
+        Ok(provider) => provider,
+        Err(e) => return oidc_error_response(&state, e),
+    };
+    match oidc_service::start_login(state.pool.clone(), &provider).await {
+        Ok(login) => {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                header::SET_COOKIE,
+                HeaderValue::from_str(&format!(
+                    "{}={}; HttpOnly; Secure; Path=/auth/oidc; SameSite=Lax; Max-Age=600",
+                    STATE_COOKIE, login.state
+                ))
+                .unwrap(),
+            );
+            return (headers, Redirect::to(&login.url)).into_response();
+        }
+        Err(e) => return oidc_error_response(&state, e),
+    }
+}
+
+// Where the provider sends the player back to, signing them in
+pub async fn oidc_callback(
+    Path(provider): Path<String>,
+    Query(query): Query<OidcCallbackQuery>,
+    cookies: CookieJar,
+    request_headers: HeaderMap,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let expected_state = cookies.get(STATE_COOKIE).map(|cookie| cookie.value());
+    let user_id = match finish_login(&state, &provider, query, expected_state).await {
+        Ok(user_id) => user_id,
+        Err(e) => return oidc_error_response(&state, e),
+    };
+
+    let user_agent = auth_handlers::user_agent(&request_headers);
+    match users_service::start_session(&state.pool, user_id, user_agent).await {
+        Ok((token, session)) => {
+            let mut headers = HeaderMap::new();
+            auth_handlers::set_session_cookies(&mut headers, &token, Some(&session.refresh_token));
+            headers.append(header::SET_COOKIE, clear_state_cookie());
+            // The session cookies are SameSite=Strict, so the browser won't send them on a
+            // redirect that started at the provider. Moving on from our own page works.
+            let body = state
+                .templates
+                .render("auth/oidc", &serde_json::json!({ "error": null }))
+                .unwrap();
+            return (headers, Html(body)).into_response();
+        }
+        Err(e) => {
+            tracing::error!("error starting session {}", e.to_string());
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "There was an error signing in. Please try again later...",
+            )
+                .into_response();
+        }
+    }
+}
+
+async fn finish_login(
+    state: &AppState,
+    provider: &str,
+    query: OidcCallbackQuery,
+    expected_state: Option<&str>,
+) -> Result<Uuid, OidcError> {
+    let provider = oidc_service::provider(provider)?;
+    if let Some(error) = query.error {
+        return Err(OidcError::Denied(query.error_description.unwrap_or(error)));
+    }
+    let (Some(code), Some(login_state)) = (query.code, query.state) else {
+        return Err(OidcError::InvalidState);
+    };
+    if expected_state != Some(login_state.as_str()) {
+        return Err(OidcError::InvalidState);
+    }
+    return oidc_service::finish_login(state.pool.clone(), &provider, &code, &login_state).await;
+}
+
+fn clear_state_cookie() -> HeaderValue {
+    return HeaderValue::from_str(&format!(
+        "{}=; HttpOnly; Secure; Path=/auth/oidc; SameSite=Lax; Max-Age=0",
+        STATE_COOKIE
+    ))
+    .unwrap();
+}
+
+fn oidc_error_response(state: &AppState, e: OidcError) -> Response {
+    let status = match e {
+        OidcError::UnknownProvider => StatusCode::NOT_FOUND,
+        OidcError::Provider(_) | OidcError::Http(_) => {
+            tracing::error!("oidc provider error {}", e.to_string());
+            StatusCode::BAD_GATEWAY
+        }
+        OidcError::Database(ref db_err) => {
+            tracing::error!("{:?}", db_err);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "There was an error signing in. Please try again later...",
+            )
+                .into_response();
+        }
+        _ => StatusCode::BAD_REQUEST,
+    };
+    let mut headers = HeaderMap::new();
+    headers.insert(header::SET_COOKIE, clear_state_cookie());
+    let body = state
+        .templates
+        .render("auth/oidc", &serde_json::json!({ "error": e.to_string() }))
+        .unwrap();
+    return (status, headers, Html(body)).into_response();
+}
@@ -0,0 +1,48 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+    Extension,
+};
+use uuid::Uuid;
+
+use crate::{
+    services::{profile_service, users_service::Claims},
+    AppState,
+};
+
+pub async fn handle_own_profile(
+    Extension(player): Extension<Claims>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    return render_profile(&state, &player.username, &player).await;
+}
+
+pub async fn handle_profile(
+    Path(username): Path<String>,
+    Extension(player): Extension<Claims>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    return render_profile(&state, &username, &player).await;
+}
+
+async fn render_profile(state: &AppState, username: &str, viewer: &Claims) -> Response {
+    let Ok(viewer_id) = Uuid::parse_str(&viewer.id) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    match profile_service::get_profile(state.pool.clone(), username, viewer_id).await {
+        Ok(Some(profile)) => {
+            let body = state.templates.render("profile", &profile).unwrap();
+            return Html(body).into_response();
+        }
+        Ok(None) => return (StatusCode::NOT_FOUND, "Player not found").into_response(),
+        Err(e) => {
+            tracing::error!("error loading profile {}", e.to_string());
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "There was an error loading the profile. Please try again later...",
+            )
+                .into_response();
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{Html, IntoResponse},
+    Json,
+};
+use serde_json::json;
+
+use crate::{
+    handlers::wants_json,
+    services::{matchmaking_service::GameType, season_service},
+    AppState,
+};
+
+#[derive(serde::Deserialize)]
+pub struct SeasonQuery {
+    mode: Option<String>,
+}
+
+pub async fn handle_seasons(
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    match season_service::list_seasons(state.pool).await {
+        Ok(seasons) => {
+            if wants_json(&headers) {
+                return Json(seasons).into_response();
+            }
+            let data = json!({ "seasons": seasons });
+            let body = state.templates.render("seasons", &data).unwrap();
+            return Html(body).into_response();
+        }
+        Err(e) => {
+            tracing::error!("error listing seasons {}", e.to_string());
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "There was an error loading seasons. Please try again later...",
+            )
+                .into_response();
+        }
+    }
+}
+
+// Archived standings of a season, for ranked unless another mode is asked for
+pub async fn handle_season(
+    Path(season_id): Path<i32>,
+    Query(query): Query<SeasonQuery>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let gamemode = match query
+        .mode
+        .as_deref()
+        .unwrap_or("ranked")
+        .parse::<GameType>()
+    {
+        Ok(gamemode) => gamemode,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+
+    match season_service::get_season_standings(state.pool, season_id, gamemode).await {
+        Ok(Some(view)) => {
+            if wants_json(&headers) {
+                return Json(view).into_response();
+            }
+            let body = state.templates.render("season", &view).unwrap();
+            return Html(body).into_response();
+        }
+        Ok(None) => return (StatusCode::NOT_FOUND, "Season not found").into_response(),
+        Err(e) => {
+            tracing::error!("error loading season standings {}", e.to_string());
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "There was an error loading the season. Please try again later...",
+            )
+                .into_response();
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Response},
+    Extension, Form, Json,
+};
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    handlers::wants_json,
+    services::{
+        tournament_service::{self, TournamentError, TournamentFormat},
+        users_service::Claims,
+    },
+    AppState,
+};
+
+#[derive(serde::Deserialize)]
+pub struct NewTournamentRequest {
+    name: String,
+    format: String,
+    size: i32,
+    starts_at: String,
+}
+
+pub async fn handle_tournaments(
+    Extension(player): Extension<Claims>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    return render_tournaments(&state, &player).await;
+}
+
+pub async fn handle_create_tournament(
+    Extension(player): Extension<Claims>,
+    State(state): State<AppState>,
+    Form(form): Form<NewTournamentRequest>,
+) -> impl IntoResponse {
+    let Ok(player_id) = Uuid::parse_str(&player.id) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    let format = match form.format.parse::<TournamentFormat>() {
+        Ok(format) => format,
+        Err(e) => return tournament_error_response(e),
+    };
+
+    match tournament_service::create_tournament(
+        state.pool.clone(),
+        player_id,
+        &form.name,
+        format,
+        form.size,
+        &form.starts_at,
+    )
+    .await
+    {
+        Ok(tournament_id) => return render_tournament(&state, tournament_id, &player).await,
+        Err(e) => return tournament_error_response(e),
+    }
+}
+
+pub async fn handle_tournament(
+    Path(tournament_id): Path<Uuid>,
+    Extension(player): Extension<Claims>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    return render_tournament(&state, tournament_id, &player).await;
+}
+
+pub async fn handle_register(
+    Path(tournament_id): Path<Uuid>,
+    Extension(player): Extension<Claims>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let Ok(player_id) = Uuid::parse_str(&player.id) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    if let Err(e) =
+        tournament_service::register_player(state.pool.clone(), tournament_id, player_id).await
+    {
+        return tournament_error_response(e);
+    }
+    return render_tournament(&state, tournament_id, &player).await;
+}
+
+// Standings as JSON when asked for with an Accept header, otherwise as an HTML table
+pub async fn handle_standings(
+    Path(tournament_id): Path<Uuid>,
+    headers: HeaderMap,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    match tournament_service::get_standings(state.pool.clone(), tournament_id).await {
+        Ok(standings) if wants_json(&headers) => return Json(standings).into_response(),
+        Ok(standings) => {
+            let body = state
+                .templates
+                .render("tournament_standings", &standings)
+                .unwrap();
+            return Html(body).into_response();
+        }
+        Err(e) => return tournament_error_response(e),
+    }
+}
+
+async fn render_tournaments(state: &AppState, player: &Claims) -> Response {
+    let Ok(player_id) = Uuid::parse_str(&player.id) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    match tournament_service::list_tournaments(state.pool.clone(), player_id).await {
+        Ok(tournaments) => {
+            let data = json!({ "tournaments": tournaments });
+            let body = state.templates.render("tournaments", &data).unwrap();
+            return Html(body).into_response();
+        }
+        Err(e) => return tournament_error_response(e),
+    }
+}
+
+// Renders a tournament and its bracket from the point of view of the given player
+async fn render_tournament(state: &AppState, tournament_id: Uuid, player: &Claims) -> Response {
+    let Ok(player_id) = Uuid::parse_str(&player.id) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    match tournament_service::get_tournament_view(state.pool.clone(), tournament_id, player_id)
+        .await
+    {
+        Ok(tournament) => {
+            let body = state.templates.render("tournament", &tournament).unwrap();
+            return Html(body).into_response();
+        }
+        Err(e) => return tournament_error_response(e),
+    }
+}
+
+fn tournament_error_response(e: TournamentError) -> Response {
+    let status = match e {
+        TournamentError::NotFound => StatusCode::NOT_FOUND,
+        TournamentError::RegistrationClosed
+        | TournamentError::Full
+        | TournamentError::AlreadyRegistered => StatusCode::CONFLICT,
+        TournamentError::Database(ref db_err) => {
+            tracing::error!("{:?}", db_err);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "There was an error. Please try again later...",
+            )
+                .into_response();
+        }
+        _ => StatusCode::BAD_REQUEST,
+    };
+    return (status, e.to_string()).into_response();
+}
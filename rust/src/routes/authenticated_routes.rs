@@ -1,7 +1,17 @@
-use axum::{routing::get, Router};
+use axum::{
+    middleware::from_fn_with_state,
+    routing::{delete, get, post},
+    Router,
+};
 
 use crate::{
-    handlers::{dashboard_handlers, matchmaking_handlers},
+    handlers::{
+        admin_handlers, auth_handlers, dashboard_handlers, events_handlers, game_handlers,
+        history_handlers, leaderboard_handlers, matchmaking_handlers, profile_handlers,
+        season_handlers, tournament_handlers,
+    },
+    require_role,
+    services::role_service::Role,
     AppState,
 };
 
@@ -10,10 +20,6 @@ pub fn add_routes() -> Router<AppState> {
     return Router::new()
         .route("/dashboard", get(dashboard_handlers::handle_dashboard))
         .route("/gametypes", get(dashboard_handlers::handle_gametypes))
-        .route(
-            "/matchmaking/ranked/{playerid}",
-            get(matchmaking_handlers::handle_ranked),
-        )
         .route(
             "/matchmaking/casual/{playerid}",
             get(matchmaking_handlers::handle_casual),
@@ -25,5 +31,97 @@ pub fn add_routes() -> Router<AppState> {
         .route(
             "/matchmaking/ready/{playerid}",
             get(matchmaking_handlers::handle_ready),
-        );
+        )
+        .route("/logout", post(auth_handlers::log_out))
+        .route(
+            "/logout/everywhere",
+            post(auth_handlers::log_out_everywhere),
+        )
+        .route("/sessions", get(auth_handlers::get_sessions))
+        .route(
+            "/sessions/{sessionid}",
+            delete(auth_handlers::revoke_session),
+        )
+        .route(
+            "/verify_email/resend",
+            post(auth_handlers::resend_verification_email),
+        )
+        .route("/profile", get(profile_handlers::handle_own_profile))
+        .route("/profile/{username}", get(profile_handlers::handle_profile))
+        .route("/history", get(history_handlers::handle_own_history))
+        .route("/history/{username}", get(history_handlers::handle_history))
+        .route(
+            "/leaderboard",
+            get(leaderboard_handlers::handle_global_leaderboard),
+        )
+        .route(
+            "/leaderboard/{gamemode}",
+            get(leaderboard_handlers::handle_leaderboard),
+        )
+        .route("/seasons", get(season_handlers::handle_seasons))
+        .route("/seasons/{seasonid}", get(season_handlers::handle_season))
+        .route("/game/{gameid}", get(game_handlers::handle_game))
+        .route("/game/{gameid}/throw", post(game_handlers::handle_throw))
+        .route("/game/{gameid}/commit", post(game_handlers::handle_commit))
+        .route("/game/{gameid}/reveal", post(game_handlers::handle_reveal))
+        .route("/tournaments", get(tournament_handlers::handle_tournaments))
+        .route(
+            "/tournaments/{tournamentid}",
+            get(tournament_handlers::handle_tournament),
+        )
+        .route(
+            "/tournaments/{tournamentid}/standings",
+            get(tournament_handlers::handle_standings),
+        )
+        .route("/ws", get(events_handlers::handle_ws))
+        .route("/events", get(events_handlers::handle_sse))
+        .merge(member_routes())
+        .merge(guest_routes())
+        .merge(admin_routes());
+}
+
+// Routes for players with a full account. Guests can only play casual games.
+fn member_routes() -> Router<AppState> {
+    return Router::new()
+        .route(
+            "/matchmaking/ranked/{playerid}",
+            get(matchmaking_handlers::handle_ranked),
+        )
+        .route(
+            "/tournaments",
+            post(tournament_handlers::handle_create_tournament),
+        )
+        .route(
+            "/tournaments/{tournamentid}/register",
+            post(tournament_handlers::handle_register),
+        )
+        .route_layer(from_fn_with_state(Role::User, require_role));
+}
+
+// Routes for guests
+fn guest_routes() -> Router<AppState> {
+    return Router::new()
+        .route(
+            "/auth/guest/convert",
+            get(auth_handlers::get_convert_guest).post(auth_handlers::convert_guest),
+        )
+        .route_layer(from_fn_with_state(Role::Guest, require_role));
+}
+
+// Routes only admins can use
+fn admin_routes() -> Router<AppState> {
+    return Router::new()
+        .route(
+            "/matchmaking/metrics/{gamemode}",
+            get(matchmaking_handlers::handle_metrics),
+        )
+        .route(
+            "/admin/users/{username}/roles",
+            get(admin_handlers::handle_roles).post(admin_handlers::handle_grant_role),
+        )
+        .route(
+            "/admin/users/{username}/roles/{role}",
+            delete(admin_handlers::handle_revoke_role),
+        )
+        .route_layer(from_fn_with_state(Role::Admin, require_role));
 }
@@ -3,7 +3,10 @@ use axum::{
     Router,
 };
 
-use crate::{handlers::auth_handlers, AppState};
+use crate::{
+    handlers::{auth_handlers, oidc_handlers},
+    AppState,
+};
 
 pub fn add_routes() -> Router<AppState> {
     return Router::new()
@@ -15,5 +18,17 @@ pub fn add_routes() -> Router<AppState> {
         .route(
             "/auth/password_reset",
             get(auth_handlers::get_password_reset),
+        )
+        .route(
+            "/auth/password_reset/confirm",
+            get(auth_handlers::get_password_reset_confirm)
+                .post(auth_handlers::password_reset_confirm),
+        )
+        .route("/auth/verify_email", get(auth_handlers::verify_email))
+        .route("/auth/guest", post(auth_handlers::play_as_guest))
+        .route("/auth/oidc/{provider}", get(oidc_handlers::oidc_login))
+        .route(
+            "/auth/oidc/{provider}/callback",
+            get(oidc_handlers::oidc_callback),
         );
 }
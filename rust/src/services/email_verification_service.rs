@@ -0,0 +1,133 @@
+use handlebars::Handlebars;
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+use uuid::Uuid;
+
+use super::{
+    mail_service::{self, Email},
+    token_service::{self, hash_token},
+};
+
+const DEFAULT_TOKEN_HOURS: i32 = 48;
+
+// A freshly issued token and who it's for
+pub struct VerificationToken {
+    pub username: String,
+    pub email: String,
+    pub token: String,
+    // How long the token is valid for
+    pub hours: i32,
+}
+
+// Whether players have to verify their email before they can queue for ranked,
+// set with REQUIRE_VERIFIED_EMAIL_FOR_RANKED
+pub fn ranked_requires_verified_email() -> bool {
+    return dotenv::var("REQUIRE_VERIFIED_EMAIL_FOR_RANKED")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(false);
+}
+
+// Issues a verification token for the account with this email, replacing any older ones.
+// Returns None when there's no such account or it's already verified.
+pub async fn create_verification_token(
+    pool: Pool<Postgres>,
+    email: &str,
+) -> Result<Option<VerificationToken>, sqlx::Error> {
+    let user = sqlx::query!(
+        r#"SELECT id, username, email AS "email!" FROM users WHERE email = $1 AND email_verified_at IS NULL;"#,
+        email
+    )
+    .fetch_optional(&pool)
+    .await?;
+    let Some(user) = user else {
+        return Ok(None);
+    };
+
+    let token = token_service::new_token();
+    let hours = dotenv::var("EMAIL_VERIFICATION_TOKEN_HOURS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_TOKEN_HOURS);
+
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
+        "DELETE FROM email_verification_tokens WHERE user_id = $1;",
+        user.id
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "INSERT INTO email_verification_tokens (user_id, token_hash, expires_at)
+        VALUES ($1, $2, NOW() + make_interval(hours => $3));",
+        user.id,
+        hash_token(&token),
+        hours
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    return Ok(Some(VerificationToken {
+        username: user.username,
+        email: user.email,
+        token,
+        hours,
+    }));
+}
+
+pub fn verification_email(templates: &Handlebars<'_>, verification: &VerificationToken) -> Email {
+    return mail_service::render_email(
+        templates,
+        "verify_email",
+        &verification.email,
+        "Verify your Roshamble email",
+        &json!({
+            "username": verification.username,
+            "app_url": mail_service::app_url(),
+            "token": verification.token,
+            "hours": verification.hours,
+        }),
+    );
+}
+
+// Marks the email of the token's account as verified and uses the token up.
+// Returns the account's username, or None if the token is unknown or expired.
+pub async fn verify_email(
+    pool: Pool<Postgres>,
+    token: &str,
+) -> Result<Option<String>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let user_id = sqlx::query_scalar!(
+        "DELETE FROM email_verification_tokens
+        WHERE token_hash = $1 AND expires_at > NOW()
+        RETURNING user_id;",
+        hash_token(token)
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(user_id) = user_id else {
+        return Ok(None);
+    };
+
+    let username = sqlx::query_scalar!(
+        "UPDATE users SET email_verified_at = COALESCE(email_verified_at, NOW())
+        WHERE id = $1
+        RETURNING username;",
+        user_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    return Ok(Some(username));
+}
+
+pub async fn is_verified(pool: Pool<Postgres>, user_id: Uuid) -> Result<bool, sqlx::Error> {
+    let verified = sqlx::query_scalar!(
+        r#"SELECT email_verified_at IS NOT NULL AS "verified!" FROM users WHERE id = $1;"#,
+        user_id
+    )
+    .fetch_optional(&pool)
+    .await?;
+    return Ok(verified.unwrap_or(false));
+}
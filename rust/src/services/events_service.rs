@@ -0,0 +1,172 @@
+use std::time::Duration;
+
+use sqlx::{postgres::PgListener, PgExecutor};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+// Postgres channel events are sent over so every server instance sees them
+const EVENTS_CHANNEL: &str = "roshamble_events";
+// How many events a slow subscriber can fall behind before it starts missing some
+const EVENTS_BUFFER: usize = 1024;
+
+// Events pushed to clients while they queue and play.
+//
+// Every event is sent to clients as a JSON object tagged with its "type":
+//
+//   {"type": "queue_update", "game_type": "ranked", "queued": 3}
+//   {"type": "match_found", "match_id": 12, "game_type": "ranked", "player1_id": "<uuid>", "player2_id": "<uuid>"}
+//   {"type": "match_expired", "match_id": 12, "player1_id": "<uuid>", "player2_id": "<uuid>"}
+//   {"type": "game_started", "game_id": "<uuid>", "match_id": 12, "player1_id": "<uuid>", "player2_id": "<uuid>"}
+//   {"type": "opponent_threw", "game_id": "<uuid>", "round": 1, "player_id": "<uuid>", "opponent_id": "<uuid>"}
+//   {"type": "round_result", "game_id": "<uuid>", "round": 1, "player1_id": "<uuid>", "player2_id": "<uuid>",
+//    "player1_throw": "rock", "player2_throw": "paper", "result": "player2"}
+//   {"type": "match_over", "game_id": "<uuid>", "player1_id": "<uuid>", "player2_id": "<uuid>", "winner_id": "<uuid>"}
+//   {"type": "tournament_update", "tournament_id": "<uuid>"}
+//
+// Queue and tournament updates go to everyone. Everything else only goes to the players involved,
+// and "opponent_threw" only to the opponent of the player who threw (player_id).
+// Throws are only ever sent once a round is resolved.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum GameEvent {
+    QueueUpdate {
+        game_type: String,
+        queued: i64,
+    },
+    MatchFound {
+        match_id: i32,
+        game_type: String,
+        player1_id: Uuid,
+        player2_id: Uuid,
+    },
+    MatchExpired {
+        match_id: i32,
+        player1_id: Uuid,
+        player2_id: Uuid,
+    },
+    GameStarted {
+        game_id: Uuid,
+        match_id: i32,
+        player1_id: Uuid,
+        player2_id: Uuid,
+    },
+    OpponentThrew {
+        game_id: Uuid,
+        round: i32,
+        player_id: Uuid,
+        opponent_id: Uuid,
+    },
+    RoundResult {
+        game_id: Uuid,
+        round: i32,
+        player1_id: Uuid,
+        player2_id: Uuid,
+        player1_throw: String,
+        player2_throw: String,
+        result: String,
+    },
+    MatchOver {
+        game_id: Uuid,
+        player1_id: Uuid,
+        player2_id: Uuid,
+        winner_id: Uuid,
+    },
+    TournamentUpdate {
+        tournament_id: Uuid,
+    },
+}
+
+impl GameEvent {
+    // The "type" tag the event is serialized with
+    pub fn name(&self) -> &'static str {
+        match self {
+            GameEvent::QueueUpdate { .. } => "queue_update",
+            GameEvent::MatchFound { .. } => "match_found",
+            GameEvent::MatchExpired { .. } => "match_expired",
+            GameEvent::GameStarted { .. } => "game_started",
+            GameEvent::OpponentThrew { .. } => "opponent_threw",
+            GameEvent::RoundResult { .. } => "round_result",
+            GameEvent::MatchOver { .. } => "match_over",
+            GameEvent::TournamentUpdate { .. } => "tournament_update",
+        }
+    }
+
+    // Whether this event should be delivered to the given player
+    pub fn is_for(&self, player_id: Uuid) -> bool {
+        match self {
+            GameEvent::QueueUpdate { .. } | GameEvent::TournamentUpdate { .. } => true,
+            GameEvent::MatchFound {
+                player1_id,
+                player2_id,
+                ..
+            }
+            | GameEvent::MatchExpired {
+                player1_id,
+                player2_id,
+                ..
+            }
+            | GameEvent::GameStarted {
+                player1_id,
+                player2_id,
+                ..
+            }
+            | GameEvent::RoundResult {
+                player1_id,
+                player2_id,
+                ..
+            }
+            | GameEvent::MatchOver {
+                player1_id,
+                player2_id,
+                ..
+            } => *player1_id == player_id || *player2_id == player_id,
+            GameEvent::OpponentThrew { opponent_id, .. } => *opponent_id == player_id,
+        }
+    }
+}
+
+pub fn new_event_bus() -> broadcast::Sender<GameEvent> {
+    let (sender, _) = broadcast::channel(EVENTS_BUFFER);
+    return sender;
+}
+
+// Publishes an event to every server instance. When called inside a transaction
+// the event is only delivered once the transaction commits.
+pub async fn publish<'e, E: PgExecutor<'e>>(
+    executor: E,
+    event: &GameEvent,
+) -> Result<(), sqlx::Error> {
+    let payload = serde_json::to_string(event).map_err(|e| sqlx::Error::Encode(Box::new(e)))?;
+    sqlx::query!("SELECT pg_notify($1, $2);", EVENTS_CHANNEL, payload)
+        .execute(executor)
+        .await?;
+    return Ok(());
+}
+
+// Runs forever, forwarding events published by any server instance to this instance's subscribers.
+// The listener holds its connection for as long as it runs, so it gets its own rather
+// than taking one from the pool.
+pub async fn run_event_listener(database_url: String, sender: broadcast::Sender<GameEvent>) {
+    loop {
+        if let Err(e) = listen(&database_url, &sender).await {
+            tracing::error!("error listening for events {}", e.to_string());
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+async fn listen(
+    database_url: &str,
+    sender: &broadcast::Sender<GameEvent>,
+) -> Result<(), sqlx::Error> {
+    let mut listener = PgListener::connect(database_url).await?;
+    listener.listen(EVENTS_CHANNEL).await?;
+    loop {
+        let notification = listener.recv().await?;
+        match serde_json::from_str::<GameEvent>(notification.payload()) {
+            // Sending only fails when nobody is subscribed, which is fine
+            Ok(event) => _ = sender.send(event),
+            Err(e) => tracing::error!("error decoding event {}", e.to_string()),
+        }
+    }
+}
@@ -0,0 +1,981 @@
+use std::{fmt, str::FromStr, time::Duration};
+
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Postgres, Transaction};
+use uuid::Uuid;
+
+use super::{
+    events_service::{self, GameEvent},
+    matchmaking_service::GameType,
+    rating_service, tournament_service,
+};
+
+// How often games are checked for players who stopped playing
+const GAME_SWEEP_INTERVAL: Duration = Duration::from_secs(15);
+// How long a game can go without a round being played before it's forfeited
+const DEFAULT_GAME_TIMEOUT_MINUTES: i32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Throw {
+    Rock,
+    Paper,
+    Scissors,
+}
+
+impl Throw {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Throw::Rock => "rock",
+            Throw::Paper => "paper",
+            Throw::Scissors => "scissors",
+        }
+    }
+
+    pub fn beats(&self, other: Throw) -> bool {
+        matches!(
+            (self, other),
+            (Throw::Rock, Throw::Scissors)
+                | (Throw::Paper, Throw::Rock)
+                | (Throw::Scissors, Throw::Paper)
+        )
+    }
+}
+
+impl FromStr for Throw {
+    type Err = GameError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "rock" => Ok(Throw::Rock),
+            "paper" => Ok(Throw::Paper),
+            "scissors" => Ok(Throw::Scissors),
+            _ => Err(GameError::InvalidThrow(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Player1,
+    Player2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoundResult {
+    Winner(Side),
+    Tie,
+}
+
+impl RoundResult {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RoundResult::Winner(Side::Player1) => "player1",
+            RoundResult::Winner(Side::Player2) => "player2",
+            RoundResult::Tie => "tie",
+        }
+    }
+}
+
+pub fn resolve_round(player1: Throw, player2: Throw) -> RoundResult {
+    if player1.beats(player2) {
+        return RoundResult::Winner(Side::Player1);
+    }
+    if player2.beats(player1) {
+        return RoundResult::Winner(Side::Player2);
+    }
+    return RoundResult::Tie;
+}
+
+// A best-of-N series of rounds. Ties are replayed and don't count towards N.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Series {
+    best_of: u8,
+    player1_wins: u8,
+    player2_wins: u8,
+}
+
+impl Series {
+    pub fn new(best_of: u8) -> Result<Series, GameError> {
+        if ![1, 3, 5].contains(&best_of) {
+            return Err(GameError::InvalidBestOf(best_of));
+        }
+        return Ok(Series {
+            best_of,
+            player1_wins: 0,
+            player2_wins: 0,
+        });
+    }
+
+    pub fn record_round(
+        &mut self,
+        player1: Throw,
+        player2: Throw,
+    ) -> Result<RoundResult, GameError> {
+        if self.winner().is_some() {
+            return Err(GameError::GameOver);
+        }
+        let result = resolve_round(player1, player2);
+        match result {
+            RoundResult::Winner(Side::Player1) => self.player1_wins += 1,
+            RoundResult::Winner(Side::Player2) => self.player2_wins += 1,
+            RoundResult::Tie => {}
+        }
+        return Ok(result);
+    }
+
+    pub fn wins_needed(&self) -> u8 {
+        return self.best_of / 2 + 1;
+    }
+
+    pub fn score(&self) -> (u8, u8) {
+        return (self.player1_wins, self.player2_wins);
+    }
+
+    pub fn winner(&self) -> Option<Side> {
+        if self.player1_wins >= self.wins_needed() {
+            return Some(Side::Player1);
+        }
+        if self.player2_wins >= self.wins_needed() {
+            return Some(Side::Player2);
+        }
+        return None;
+    }
+}
+
+#[derive(Debug)]
+pub enum GameError {
+    InvalidBestOf(u8),
+    InvalidThrow(String),
+    NotFound,
+    NotAPlayer,
+    AlreadyThrown,
+    GameOver,
+    CommitRequired,
+    CommitNotRequired,
+    InvalidCommitment,
+    NotReadyToReveal,
+    CommitmentMismatch,
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for GameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GameError::InvalidBestOf(n) => write!(f, "A series can't be best of {}", n),
+            GameError::InvalidThrow(t) => write!(f, "{} is not a valid throw", t),
+            GameError::NotFound => write!(f, "Game not found"),
+            GameError::NotAPlayer => write!(f, "You are not playing in this game"),
+            GameError::AlreadyThrown => write!(f, "You already threw this round"),
+            GameError::GameOver => write!(f, "This game is already over"),
+            GameError::CommitRequired => {
+                write!(
+                    f,
+                    "This game requires committing to a throw before revealing it"
+                )
+            }
+            GameError::CommitNotRequired => write!(f, "This game doesn't use commitments"),
+            GameError::InvalidCommitment => write!(f, "Commitments must be a hex SHA-256 hash"),
+            GameError::NotReadyToReveal => {
+                write!(
+                    f,
+                    "Throws can only be revealed once both players have committed"
+                )
+            }
+            GameError::CommitmentMismatch => {
+                write!(f, "That throw doesn't match what you committed to")
+            }
+            GameError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl From<sqlx::Error> for GameError {
+    fn from(e: sqlx::Error) -> Self {
+        return GameError::Database(e);
+    }
+}
+
+// Starts the game for a match once both players are ready.
+pub async fn create_game(
+    tx: &mut Transaction<'_, Postgres>,
+    match_id: i32,
+) -> Result<Uuid, sqlx::Error> {
+    let game_type = sqlx::query_scalar!(
+        "SELECT game_type FROM matchmaking_matches WHERE match_id = $1;",
+        match_id
+    )
+    .fetch_one(&mut **tx)
+    .await?;
+    let game_type = game_type.parse::<GameType>().unwrap_or(GameType::Casual);
+
+    return sqlx::query_scalar!(
+        r#"INSERT INTO games (match_id, game_type, best_of, player1_id, player2_id, commit_reveal)
+        SELECT match_id, game_type, $2, player1_id, player2_id, $3
+        FROM matchmaking_matches WHERE match_id = $1
+        RETURNING id;"#,
+        match_id,
+        game_type.best_of() as i32,
+        game_type.uses_commit_reveal(),
+    )
+    .fetch_one(&mut **tx)
+    .await;
+}
+
+struct GameRow {
+    player1_id: Uuid,
+    player2_id: Uuid,
+    match_id: Option<i32>,
+    game_type: String,
+    best_of: i32,
+    status: String,
+    commit_reveal: bool,
+}
+
+#[derive(Clone)]
+struct RoundRow {
+    round_number: i32,
+    player1_throw: Option<String>,
+    player2_throw: Option<String>,
+    player1_commitment: Option<String>,
+    player2_commitment: Option<String>,
+    result: Option<String>,
+}
+
+impl RoundRow {
+    fn throw(&self, side: Side) -> &Option<String> {
+        match side {
+            Side::Player1 => &self.player1_throw,
+            Side::Player2 => &self.player2_throw,
+        }
+    }
+
+    fn commitment(&self, side: Side) -> &Option<String> {
+        match side {
+            Side::Player1 => &self.player1_commitment,
+            Side::Player2 => &self.player2_commitment,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ThrowOutcome {
+    WaitingForOpponent,
+    RoundResolved(RoundResult),
+    GameOver(Side),
+}
+
+// Records a player's throw for the current round, resolving the round
+// (and possibly the series) once both throws are in.
+pub async fn submit_throw(
+    pool: Pool<Postgres>,
+    game_id: Uuid,
+    player_id: Uuid,
+    throw: Throw,
+) -> Result<ThrowOutcome, GameError> {
+    let mut tx = pool.begin().await?;
+    let (game, side) = lock_game(&mut tx, game_id, player_id).await?;
+    if game.commit_reveal {
+        return Err(GameError::CommitRequired);
+    }
+
+    let rounds = load_rounds(&mut tx, game_id).await?;
+    let round = open_round(&mut tx, game_id, &rounds).await?;
+    if round.throw(side).is_some() {
+        return Err(GameError::AlreadyThrown);
+    }
+
+    let outcome = record_throw(&mut tx, game_id, &game, &rounds, &round, side, throw).await?;
+    tx.commit().await?;
+    return Ok(outcome);
+}
+
+// Records the hash a player commits to for the current round of a commit-reveal game.
+// The commitment is the lowercase hex SHA-256 of "<throw>:<salt>", see `commitment_for`.
+pub async fn submit_commitment(
+    pool: Pool<Postgres>,
+    game_id: Uuid,
+    player_id: Uuid,
+    commitment: String,
+) -> Result<ThrowOutcome, GameError> {
+    let commitment = commitment.to_lowercase();
+    if commitment.len() != 64 || !commitment.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(GameError::InvalidCommitment);
+    }
+
+    let mut tx = pool.begin().await?;
+    let (game, side) = lock_game(&mut tx, game_id, player_id).await?;
+    if !game.commit_reveal {
+        return Err(GameError::CommitNotRequired);
+    }
+
+    let rounds = load_rounds(&mut tx, game_id).await?;
+    let round = open_round(&mut tx, game_id, &rounds).await?;
+    if round.commitment(side).is_some() {
+        return Err(GameError::AlreadyThrown);
+    }
+
+    sqlx::query!(
+        "UPDATE game_rounds SET
+            player1_commitment = CASE WHEN $3 THEN $4 ELSE player1_commitment END,
+            player2_commitment = CASE WHEN $3 THEN player2_commitment ELSE $4 END
+        WHERE game_id = $1 AND round_number = $2;",
+        game_id,
+        round.round_number,
+        side == Side::Player1,
+        commitment,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    events_service::publish(&mut *tx, &opponent_threw(game_id, &game, &round, side)).await?;
+    tx.commit().await?;
+    return Ok(ThrowOutcome::WaitingForOpponent);
+}
+
+// Reveals the throw behind a player's commitment. Only accepted once both players
+// have committed, and only if the throw and salt hash to the stored commitment.
+pub async fn reveal_throw(
+    pool: Pool<Postgres>,
+    game_id: Uuid,
+    player_id: Uuid,
+    throw: Throw,
+    salt: String,
+) -> Result<ThrowOutcome, GameError> {
+    let mut tx = pool.begin().await?;
+    let (game, side) = lock_game(&mut tx, game_id, player_id).await?;
+    if !game.commit_reveal {
+        return Err(GameError::CommitNotRequired);
+    }
+
+    let rounds = load_rounds(&mut tx, game_id).await?;
+    let Some(round) = rounds.iter().find(|r| r.result.is_none()) else {
+        return Err(GameError::NotReadyToReveal);
+    };
+    let (Some(player1_commitment), Some(player2_commitment)) =
+        (&round.player1_commitment, &round.player2_commitment)
+    else {
+        return Err(GameError::NotReadyToReveal);
+    };
+    if round.throw(side).is_some() {
+        return Err(GameError::AlreadyThrown);
+    }
+
+    let commitment = match side {
+        Side::Player1 => player1_commitment,
+        Side::Player2 => player2_commitment,
+    };
+    if !verify_commitment(commitment, throw, &salt) {
+        return Err(GameError::CommitmentMismatch);
+    }
+
+    let outcome = record_throw(&mut tx, game_id, &game, &rounds, round, side, throw).await?;
+    tx.commit().await?;
+    return Ok(outcome);
+}
+
+pub fn commitment_for(throw: Throw, salt: &str) -> String {
+    let digest = Sha256::digest(format!("{}:{}", throw.as_str(), salt));
+    return hex::encode(digest);
+}
+
+pub fn verify_commitment(commitment: &str, throw: Throw, salt: &str) -> bool {
+    return commitment_for(throw, salt) == commitment.to_lowercase();
+}
+
+async fn lock_game(
+    tx: &mut Transaction<'_, Postgres>,
+    game_id: Uuid,
+    player_id: Uuid,
+) -> Result<(GameRow, Side), GameError> {
+    let game = sqlx::query_as!(
+        GameRow,
+        "SELECT player1_id, player2_id, match_id, game_type, best_of, status, commit_reveal
+        FROM games
+        WHERE id = $1 FOR UPDATE;",
+        game_id
+    )
+    .fetch_optional(&mut **tx)
+    .await?
+    .ok_or(GameError::NotFound)?;
+
+    let side = if game.player1_id == player_id {
+        Side::Player1
+    } else if game.player2_id == player_id {
+        Side::Player2
+    } else {
+        return Err(GameError::NotAPlayer);
+    };
+    if game.status != "in_progress" {
+        return Err(GameError::GameOver);
+    }
+    return Ok((game, side));
+}
+
+async fn load_rounds(
+    tx: &mut Transaction<'_, Postgres>,
+    game_id: Uuid,
+) -> Result<Vec<RoundRow>, sqlx::Error> {
+    return sqlx::query_as!(
+        RoundRow,
+        "SELECT round_number, player1_throw, player2_throw, player1_commitment,
+            player2_commitment, result
+        FROM game_rounds
+        WHERE game_id = $1 ORDER BY round_number;",
+        game_id
+    )
+    .fetch_all(&mut **tx)
+    .await;
+}
+
+// Picks up the open round, or starts the next one
+async fn open_round(
+    tx: &mut Transaction<'_, Postgres>,
+    game_id: Uuid,
+    rounds: &[RoundRow],
+) -> Result<RoundRow, sqlx::Error> {
+    if let Some(round) = rounds.iter().find(|r| r.result.is_none()) {
+        return Ok(round.clone());
+    }
+    let round_number = rounds.len() as i32 + 1;
+    sqlx::query!(
+        "INSERT INTO game_rounds (game_id, round_number) VALUES ($1, $2);",
+        game_id,
+        round_number
+    )
+    .execute(&mut **tx)
+    .await?;
+    return Ok(RoundRow {
+        round_number,
+        player1_throw: None,
+        player2_throw: None,
+        player1_commitment: None,
+        player2_commitment: None,
+        result: None,
+    });
+}
+
+// Stores a throw and resolves the round (and possibly the series) once both throws are in
+async fn record_throw(
+    tx: &mut Transaction<'_, Postgres>,
+    game_id: Uuid,
+    game: &GameRow,
+    rounds: &[RoundRow],
+    round: &RoundRow,
+    side: Side,
+    throw: Throw,
+) -> Result<ThrowOutcome, GameError> {
+    sqlx::query!(
+        "UPDATE game_rounds SET
+            player1_throw = CASE WHEN $3 THEN $4 ELSE player1_throw END,
+            player2_throw = CASE WHEN $3 THEN player2_throw ELSE $4 END
+        WHERE game_id = $1 AND round_number = $2;",
+        game_id,
+        round.round_number,
+        side == Side::Player1,
+        throw.as_str(),
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    let (player1_throw, player2_throw) = match side {
+        Side::Player1 => (Some(throw), parse_throw(&round.player2_throw)?),
+        Side::Player2 => (parse_throw(&round.player1_throw)?, Some(throw)),
+    };
+    let (Some(player1_throw), Some(player2_throw)) = (player1_throw, player2_throw) else {
+        // In commit-reveal games the opponent already heard about the commitment
+        if !game.commit_reveal {
+            events_service::publish(&mut **tx, &opponent_threw(game_id, game, round, side)).await?;
+        }
+        return Ok(ThrowOutcome::WaitingForOpponent);
+    };
+
+    let mut series = replay_series(game.best_of, rounds)?;
+    let result = series.record_round(player1_throw, player2_throw)?;
+    sqlx::query!(
+        "UPDATE game_rounds SET result = $3, resolved_at = NOW()
+        WHERE game_id = $1 AND round_number = $2;",
+        game_id,
+        round.round_number,
+        result.as_str(),
+    )
+    .execute(&mut **tx)
+    .await?;
+    events_service::publish(
+        &mut **tx,
+        &GameEvent::RoundResult {
+            game_id,
+            round: round.round_number,
+            player1_id: game.player1_id,
+            player2_id: game.player2_id,
+            player1_throw: player1_throw.as_str().to_string(),
+            player2_throw: player2_throw.as_str().to_string(),
+            result: result.as_str().to_string(),
+        },
+    )
+    .await?;
+
+    let Some(winner) = series.winner() else {
+        return Ok(ThrowOutcome::RoundResolved(result));
+    };
+
+    finish_game(tx, game_id, game, winner).await?;
+    return Ok(ThrowOutcome::GameOver(winner));
+}
+
+// Marks the game won, rates it and lets the tournament (if any) and both players know
+async fn finish_game(
+    tx: &mut Transaction<'_, Postgres>,
+    game_id: Uuid,
+    game: &GameRow,
+    winner: Side,
+) -> Result<(), sqlx::Error> {
+    let (winner_id, loser_id) = match winner {
+        Side::Player1 => (game.player1_id, game.player2_id),
+        Side::Player2 => (game.player2_id, game.player1_id),
+    };
+    sqlx::query!(
+        "UPDATE games SET status = 'finished', winner_id = $2, finished_at = NOW() WHERE id = $1;",
+        game_id,
+        winner_id
+    )
+    .execute(&mut **tx)
+    .await?;
+    // Every mode keeps its own rating, so casual results never move the ranked ladder
+    if let Ok(game_type) = game.game_type.parse::<GameType>() {
+        rating_service::apply_game_result(tx, game_type, winner_id, loser_id).await?;
+    }
+    sqlx::query!(
+        "UPDATE matchmaking_matches SET status = 'finished' WHERE match_id = $1;",
+        game.match_id
+    )
+    .execute(&mut **tx)
+    .await?;
+    if let Some(match_id) = game.match_id {
+        tournament_service::record_match_result(tx, match_id, winner_id).await?;
+    }
+    events_service::publish(
+        &mut **tx,
+        &GameEvent::MatchOver {
+            game_id,
+            player1_id: game.player1_id,
+            player2_id: game.player2_id,
+            winner_id,
+        },
+    )
+    .await?;
+    return Ok(());
+}
+
+// Runs forever, forfeiting games where nobody has played a round for a while, so a player
+// who walks away from a game still loses it and their opponent's result is recorded
+pub async fn run_game_sweeper(pool: Pool<Postgres>) {
+    let mut interval = tokio::time::interval(GAME_SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        for game_type in [GameType::Ranked, GameType::Casual, GameType::Tournament] {
+            if let Err(e) = forfeit_stalled_games(&pool, game_type).await {
+                tracing::error!("error forfeiting stalled games {}", e.to_string());
+            }
+        }
+    }
+}
+
+// Minutes a game of this type can go without a round being played, from
+// RANKED_GAME_TIMEOUT_MINUTES, CASUAL_GAME_TIMEOUT_MINUTES or TOURNAMENT_GAME_TIMEOUT_MINUTES
+fn timeout_minutes(game_type: GameType) -> i32 {
+    return dotenv::var(format!(
+        "{}_GAME_TIMEOUT_MINUTES",
+        game_type.as_str().to_uppercase()
+    ))
+    .ok()
+    .and_then(|v| v.parse().ok())
+    .unwrap_or(DEFAULT_GAME_TIMEOUT_MINUTES);
+}
+
+async fn forfeit_stalled_games(
+    pool: &Pool<Postgres>,
+    game_type: GameType,
+) -> Result<(), sqlx::Error> {
+    let timeout_minutes = timeout_minutes(game_type);
+    let stalled = sqlx::query_scalar!(
+        "SELECT g.id
+        FROM games g
+        LEFT JOIN game_rounds r ON r.game_id = g.id
+        WHERE g.game_type = $1 AND g.status = 'in_progress'
+        GROUP BY g.id
+        HAVING GREATEST(g.created_at, MAX(r.resolved_at)) < NOW() - make_interval(mins => $2);",
+        game_type.as_str(),
+        timeout_minutes,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    // One transaction per game, so one that fails doesn't hold back the rest
+    for game_id in stalled {
+        let mut tx = pool.begin().await?;
+        match forfeit_stalled_game(&mut tx, game_id, timeout_minutes).await {
+            Ok(Some(winner)) => {
+                tx.commit().await?;
+                tracing::debug!("Forfeited stalled game {} to {:?}", game_id, winner);
+            }
+            Ok(None) => {}
+            Err(e) => tracing::error!("error forfeiting game {} {}", game_id, e.to_string()),
+        }
+    }
+    return Ok(());
+}
+
+// Ends a game neither player has moved on for `timeout_minutes`.
+// Returns None if the game moved on in time.
+async fn forfeit_stalled_game(
+    tx: &mut Transaction<'_, Postgres>,
+    game_id: Uuid,
+    timeout_minutes: i32,
+) -> Result<Option<Side>, GameError> {
+    let game = sqlx::query_as!(
+        GameRow,
+        "SELECT player1_id, player2_id, match_id, game_type, best_of, status, commit_reveal
+        FROM games
+        WHERE id = $1 FOR UPDATE;",
+        game_id
+    )
+    .fetch_optional(&mut **tx)
+    .await?
+    .ok_or(GameError::NotFound)?;
+    if game.status != "in_progress" {
+        return Ok(None);
+    }
+
+    // Checked again now the game is locked, a round may have finished since the sweep looked
+    let stalled = sqlx::query_scalar!(
+        r#"SELECT GREATEST(g.created_at, MAX(r.resolved_at)) < NOW() - make_interval(mins => $2)
+            AS "stalled!"
+        FROM games g
+        LEFT JOIN game_rounds r ON r.game_id = g.id
+        WHERE g.id = $1
+        GROUP BY g.id;"#,
+        game_id,
+        timeout_minutes,
+    )
+    .fetch_one(&mut **tx)
+    .await?;
+    if !stalled {
+        return Ok(None);
+    }
+
+    let rounds = load_rounds(tx, game_id).await?;
+    let series = replay_series(game.best_of, &rounds)?;
+    let winner = forfeit_winner(&series, rounds.iter().find(|r| r.result.is_none()));
+    finish_game(tx, game_id, &game, winner).await?;
+    return Ok(Some(winner));
+}
+
+// Who wins a forfeited game: whoever got further in the open round, then whoever leads
+// the series, then player 1 (the higher seed in tournament pairings).
+fn forfeit_winner(series: &Series, open_round: Option<&RoundRow>) -> Side {
+    let progress = |side: Side| match open_round {
+        Some(round) if round.throw(side).is_some() => 2,
+        Some(round) if round.commitment(side).is_some() => 1,
+        _ => 0,
+    };
+    let (player1_wins, player2_wins) = series.score();
+    let player1_ahead = (progress(Side::Player1), player1_wins);
+    let player2_ahead = (progress(Side::Player2), player2_wins);
+    if player2_ahead > player1_ahead {
+        return Side::Player2;
+    }
+    return Side::Player1;
+}
+
+fn opponent_threw(game_id: Uuid, game: &GameRow, round: &RoundRow, side: Side) -> GameEvent {
+    let (player_id, opponent_id) = match side {
+        Side::Player1 => (game.player1_id, game.player2_id),
+        Side::Player2 => (game.player2_id, game.player1_id),
+    };
+    return GameEvent::OpponentThrew {
+        game_id,
+        round: round.round_number,
+        player_id,
+        opponent_id,
+    };
+}
+fn parse_throw(throw: &Option<String>) -> Result<Option<Throw>, GameError> {
+    return throw.as_deref().map(Throw::from_str).transpose();
+}
+
+fn replay_series(best_of: i32, rounds: &[RoundRow]) -> Result<Series, GameError> {
+    let mut series = Series::new(best_of as u8)?;
+    for round in rounds.iter().filter(|r| r.result.is_some()) {
+        if let (Some(player1), Some(player2)) = (
+            parse_throw(&round.player1_throw)?,
+            parse_throw(&round.player2_throw)?,
+        ) {
+            series.record_round(player1, player2)?;
+        }
+    }
+    return Ok(series);
+}
+
+#[derive(serde::Serialize)]
+pub struct RoundView {
+    pub number: i32,
+    pub your_throw: String,
+    pub opponent_throw: String,
+    pub result: String,
+}
+
+// A game as seen by one of its players. The opponent's throw in the open round is never included.
+#[derive(serde::Serialize)]
+pub struct GameView {
+    pub game_id: Uuid,
+    pub game_type: String,
+    pub best_of: i32,
+    pub opponent: String,
+    pub your_wins: u8,
+    pub opponent_wins: u8,
+    pub rounds: Vec<RoundView>,
+    pub current_round: i32,
+    pub commit_reveal: bool,
+    pub awaiting_your_throw: bool,
+    pub awaiting_your_reveal: bool,
+    pub finished: bool,
+    pub won: bool,
+    pub tournament_id: Option<Uuid>,
+}
+
+pub async fn get_game_view(
+    pool: Pool<Postgres>,
+    game_id: Uuid,
+    player_id: Uuid,
+) -> Result<GameView, GameError> {
+    let game = sqlx::query!(
+        r#"SELECT g.game_type, g.best_of, g.player1_id, g.player2_id, g.winner_id, g.status,
+            g.commit_reveal, p1.username AS player1_name, p2.username AS player2_name,
+            tm.tournament_id AS "tournament_id?"
+        FROM games g
+        JOIN users p1 ON p1.id = g.player1_id
+        JOIN users p2 ON p2.id = g.player2_id
+        LEFT JOIN tournament_matches tm ON tm.match_id = g.match_id
+        WHERE g.id = $1;"#,
+        game_id
+    )
+    .fetch_optional(&pool)
+    .await?
+    .ok_or(GameError::NotFound)?;
+
+    let side = if game.player1_id == player_id {
+        Side::Player1
+    } else if game.player2_id == player_id {
+        Side::Player2
+    } else {
+        return Err(GameError::NotAPlayer);
+    };
+
+    let rounds = sqlx::query_as!(
+        RoundRow,
+        "SELECT round_number, player1_throw, player2_throw, player1_commitment,
+            player2_commitment, result
+        FROM game_rounds
+        WHERE game_id = $1 ORDER BY round_number;",
+        game_id
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    let series = replay_series(game.best_of, &rounds)?;
+    let (player1_wins, player2_wins) = series.score();
+    let finished = game.status == "finished";
+
+    let open_round = rounds.iter().find(|r| r.result.is_none());
+    let current_round = open_round
+        .map(|r| r.round_number)
+        .unwrap_or(rounds.len() as i32 + 1);
+    // In commit-reveal games "throwing" means committing, revealing happens once both have committed
+    let (awaiting_your_throw, awaiting_your_reveal) = match open_round {
+        _ if finished => (false, false),
+        None => (true, false),
+        Some(r) if game.commit_reveal => (
+            r.commitment(side).is_none(),
+            r.player1_commitment.is_some()
+                && r.player2_commitment.is_some()
+                && r.throw(side).is_none(),
+        ),
+        Some(r) => (r.throw(side).is_none(), false),
+    };
+
+    let rounds = rounds
+        .into_iter()
+        .filter(|r| r.result.is_some())
+        .map(|r| {
+            let (your_throw, opponent_throw) = match side {
+                Side::Player1 => (r.player1_throw, r.player2_throw),
+                Side::Player2 => (r.player2_throw, r.player1_throw),
+            };
+            let result = match (r.result.as_deref(), side) {
+                (Some("tie"), _) => "tie",
+                (Some("player1"), Side::Player1) | (Some("player2"), Side::Player2) => "win",
+                _ => "loss",
+            };
+            RoundView {
+                number: r.round_number,
+                your_throw: your_throw.unwrap_or_default(),
+                opponent_throw: opponent_throw.unwrap_or_default(),
+                result: result.to_string(),
+            }
+        })
+        .collect();
+
+    let (your_wins, opponent_wins, opponent) = match side {
+        Side::Player1 => (player1_wins, player2_wins, game.player2_name),
+        Side::Player2 => (player2_wins, player1_wins, game.player1_name),
+    };
+
+    return Ok(GameView {
+        game_id,
+        game_type: game.game_type,
+        best_of: game.best_of,
+        opponent,
+        your_wins,
+        opponent_wins,
+        rounds,
+        current_round,
+        commit_reveal: game.commit_reveal,
+        awaiting_your_throw,
+        awaiting_your_reveal,
+        finished,
+        won: game.winner_id == Some(player_id),
+        tournament_id: game.tournament_id,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_every_throw_pair() {
+        let cases = [
+            (Throw::Rock, Throw::Rock, RoundResult::Tie),
+            (
+                Throw::Rock,
+                Throw::Paper,
+                RoundResult::Winner(Side::Player2),
+            ),
+            (
+                Throw::Rock,
+                Throw::Scissors,
+                RoundResult::Winner(Side::Player1),
+            ),
+            (
+                Throw::Paper,
+                Throw::Rock,
+                RoundResult::Winner(Side::Player1),
+            ),
+            (Throw::Paper, Throw::Paper, RoundResult::Tie),
+            (
+                Throw::Paper,
+                Throw::Scissors,
+                RoundResult::Winner(Side::Player2),
+            ),
+            (
+                Throw::Scissors,
+                Throw::Rock,
+                RoundResult::Winner(Side::Player2),
+            ),
+            (
+                Throw::Scissors,
+                Throw::Paper,
+                RoundResult::Winner(Side::Player1),
+            ),
+            (Throw::Scissors, Throw::Scissors, RoundResult::Tie),
+        ];
+        for (player1, player2, expected) in cases {
+            assert_eq!(
+                resolve_round(player1, player2),
+                expected,
+                "{:?} vs {:?}",
+                player1,
+                player2
+            );
+        }
+    }
+
+    #[test]
+    fn ties_do_not_count_towards_the_series() {
+        let mut series = Series::new(1).unwrap();
+        let result = series.record_round(Throw::Rock, Throw::Rock).unwrap();
+        assert_eq!(result, RoundResult::Tie);
+        assert_eq!(series.score(), (0, 0));
+        assert_eq!(series.winner(), None);
+    }
+
+    #[test]
+    fn series_ends_at_a_majority_of_wins() {
+        for (best_of, wins_needed) in [(1, 1), (3, 2), (5, 3)] {
+            let mut series = Series::new(best_of).unwrap();
+            assert_eq!(series.wins_needed(), wins_needed);
+            for _ in 1..wins_needed {
+                series.record_round(Throw::Paper, Throw::Rock).unwrap();
+                series.record_round(Throw::Paper, Throw::Scissors).unwrap();
+            }
+            assert_eq!(series.winner(), None, "best of {}", best_of);
+            series.record_round(Throw::Paper, Throw::Rock).unwrap();
+            assert_eq!(series.winner(), Some(Side::Player1), "best of {}", best_of);
+        }
+    }
+
+    #[test]
+    fn rejects_rounds_after_the_series_is_over() {
+        let mut series = Series::new(5).unwrap();
+        for _ in 0..3 {
+            series.record_round(Throw::Rock, Throw::Paper).unwrap();
+        }
+        assert!(matches!(
+            series.record_round(Throw::Rock, Throw::Paper),
+            Err(GameError::GameOver)
+        ));
+        assert_eq!(series.score(), (0, 3));
+    }
+
+    #[test]
+    fn rejects_even_series_lengths() {
+        assert!(matches!(Series::new(2), Err(GameError::InvalidBestOf(2))));
+    }
+
+    #[test]
+    fn forfeits_go_to_whoever_got_furthest() {
+        let mut series = Series::new(3).unwrap();
+        let mut round = RoundRow {
+            round_number: 2,
+            player1_throw: None,
+            player2_throw: None,
+            player1_commitment: None,
+            player2_commitment: None,
+            result: None,
+        };
+        assert_eq!(forfeit_winner(&series, Some(&round)), Side::Player1);
+
+        series.record_round(Throw::Rock, Throw::Paper).unwrap();
+        assert_eq!(forfeit_winner(&series, None), Side::Player2);
+
+        round.player1_commitment = Some(commitment_for(Throw::Rock, "salt"));
+        assert_eq!(forfeit_winner(&series, Some(&round)), Side::Player1);
+
+        round.player2_commitment = Some(commitment_for(Throw::Paper, "salt"));
+        round.player1_throw = Some("rock".to_string());
+        assert_eq!(forfeit_winner(&series, Some(&round)), Side::Player1);
+    }
+
+    #[test]
+    fn commitment_matches_only_the_committed_throw_and_salt() {
+        let commitment = commitment_for(Throw::Scissors, "pepper");
+        assert!(verify_commitment(&commitment, Throw::Scissors, "pepper"));
+        assert!(verify_commitment(
+            &commitment.to_uppercase(),
+            Throw::Scissors,
+            "pepper"
+        ));
+        assert!(!verify_commitment(&commitment, Throw::Rock, "pepper"));
+        assert!(!verify_commitment(&commitment, Throw::Scissors, "salt"));
+    }
+}
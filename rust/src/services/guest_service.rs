@@ -0,0 +1,317 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::http::HeaderMap;
+use sqlx::{Pool, Postgres};
+use uuid::Uuid;
+
+use super::{password_reset_service::MIN_PASSWORD_LENGTH, token_service};
+
+// How often abandoned guest accounts are cleared out
+const GUEST_CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const DEFAULT_GUEST_RETENTION_DAYS: i32 = 30;
+const DEFAULT_GUEST_SIGNUPS_PER_HOUR: usize = 5;
+const GUEST_SIGNUP_WINDOW: Duration = Duration::from_secs(60 * 60);
+// Start of every guest's made up username
+const GUEST_PREFIX: &str = "guest-";
+
+// Caps how many guest accounts one address can create an hour, so the public
+// "play as guest" button can't be used to fill the users table
+pub struct GuestLimiter {
+    // When each address recently created a guest
+    signups: Mutex<HashMap<IpAddr, Vec<Instant>>>,
+    per_hour: usize,
+    // Whether to believe the address a reverse proxy appends to X-Forwarded-For
+    trust_forwarded_for: bool,
+}
+
+impl GuestLimiter {
+    pub fn from_env() -> Self {
+        let per_hour = dotenv::var("GUEST_SIGNUPS_PER_HOUR")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_GUEST_SIGNUPS_PER_HOUR);
+        let trust_forwarded_for = dotenv::var("TRUST_FORWARDED_FOR")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(false);
+        return GuestLimiter {
+            signups: Mutex::new(HashMap::new()),
+            per_hour,
+            trust_forwarded_for,
+        };
+    }
+
+    // The address to count a sign-up against. Behind a proxy the peer is the proxy
+    // itself, so the last X-Forwarded-For hop (the one the proxy added) is used instead.
+    pub fn client_address(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        if !self.trust_forwarded_for {
+            return peer;
+        }
+        return headers
+            .get_all("X-Forwarded-For")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .last()
+            .and_then(|hop| hop.trim().parse().ok())
+            .unwrap_or(peer);
+    }
+
+    // Records a sign-up from `address`, or returns false if it has used up its allowance
+    pub fn try_acquire(&self, address: IpAddr) -> bool {
+        let mut signups = self.signups.lock().unwrap();
+        // Drop addresses that have gone quiet now and then so the map doesn't grow forever
+        if signups.len() > 10_000 {
+            signups.retain(|_, times| {
+                times
+                    .last()
+                    .is_some_and(|t| t.elapsed() < GUEST_SIGNUP_WINDOW)
+            });
+        }
+        let times = signups.entry(address).or_default();
+        times.retain(|t| t.elapsed() < GUEST_SIGNUP_WINDOW);
+        if times.len() >= self.per_hour {
+            return false;
+        }
+        times.push(Instant::now());
+        return true;
+    }
+}
+
+#[derive(Debug)]
+pub enum GuestError {
+    // The account already converted, or never was a guest
+    NotAGuest,
+    AlreadyExists,
+    // Blank, or one that would pass for a guest's made up name
+    InvalidUsername,
+    InvalidEmail,
+    PasswordTooShort,
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for GuestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GuestError::NotAGuest => write!(f, "You already have an account"),
+            GuestError::AlreadyExists => write!(f, "Email or username already exists"),
+            GuestError::InvalidUsername => {
+                write!(f, "Usernames can't be blank or start with {}", GUEST_PREFIX)
+            }
+            GuestError::InvalidEmail => write!(f, "Please enter a valid email address"),
+            GuestError::PasswordTooShort => write!(
+                f,
+                "Passwords must be at least {} characters",
+                MIN_PASSWORD_LENGTH
+            ),
+            GuestError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl From<sqlx::Error> for GuestError {
+    fn from(e: sqlx::Error) -> Self {
+        return GuestError::Database(e);
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct ConvertGuestForm {
+    pub username: String,
+    pub email: String,
+    password: String,
+}
+
+// Creates an account with only a made up username and the guest role, for playing
+// casual games without signing up
+pub async fn create_guest(pool: Pool<Postgres>) -> Result<Uuid, sqlx::Error> {
+    let username = format!("{}{}", GUEST_PREFIX, &token_service::new_token()[..8]);
+    let user_id = sqlx::query_scalar!(
+        r#"WITH new_user AS (
+            INSERT INTO users (username) VALUES ($1) RETURNING id
+        )
+        INSERT INTO user_roles (user_id, role) SELECT id, 'guest' FROM new_user
+        RETURNING user_id AS "id!";"#,
+        username
+    )
+    .fetch_one(&pool)
+    .await?;
+    return Ok(user_id);
+}
+
+// Turns a guest into a full account. It keeps its id, so games played as a guest stay in its history.
+pub async fn convert_guest(
+    pool: Pool<Postgres>,
+    user_id: Uuid,
+    form: ConvertGuestForm,
+) -> Result<(), GuestError> {
+    validate_conversion(&form)?;
+
+    let mut tx = pool.begin().await?;
+    let was_guest = sqlx::query!(
+        "DELETE FROM user_roles WHERE user_id = $1 AND role = 'guest';",
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected()
+        > 0;
+    if !was_guest {
+        return Err(GuestError::NotAGuest);
+    }
+
+    let hashed_password = bcrypt::hash(form.password, 12).unwrap();
+    let updated = sqlx::query!(
+        "UPDATE users SET username = $2, email = $3, password = $4, updated_at = NOW()
+        WHERE id = $1;",
+        user_id,
+        form.username,
+        form.email,
+        hashed_password
+    )
+    .execute(&mut *tx)
+    .await;
+    if let Err(sqlx::Error::Database(ref db_err)) = updated {
+        if db_err.is_unique_violation() {
+            return Err(GuestError::AlreadyExists);
+        }
+        // The email format is checked by the users table
+        if db_err.is_check_violation() {
+            return Err(GuestError::InvalidEmail);
+        }
+    }
+    updated?;
+
+    sqlx::query!(
+        "INSERT INTO user_roles (user_id, role) VALUES ($1, 'user') ON CONFLICT DO NOTHING;",
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    return Ok(());
+}
+
+fn validate_conversion(form: &ConvertGuestForm) -> Result<(), GuestError> {
+    let username = form.username.trim();
+    if username.is_empty() || username.to_lowercase().starts_with(GUEST_PREFIX) {
+        return Err(GuestError::InvalidUsername);
+    }
+    if form.email.trim().is_empty() {
+        return Err(GuestError::InvalidEmail);
+    }
+    if form.password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(GuestError::PasswordTooShort);
+    }
+    return Ok(());
+}
+
+// Runs forever, deleting guests nobody can sign in as anymore. Guests who played games are
+// kept so their opponents' history stays whole.
+pub async fn run_guest_cleanup(pool: Pool<Postgres>) {
+    let mut interval = tokio::time::interval(GUEST_CLEANUP_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = delete_abandoned_guests(&pool).await {
+            tracing::error!("error deleting abandoned guests {}", e.to_string());
+        }
+    }
+}
+
+async fn delete_abandoned_guests(pool: &Pool<Postgres>) -> Result<(), sqlx::Error> {
+    let days = dotenv::var("GUEST_RETENTION_DAYS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_GUEST_RETENTION_DAYS);
+    let deleted = sqlx::query!(
+        "DELETE FROM users u
+        USING user_roles r
+        WHERE r.user_id = u.id AND r.role = 'guest'
+            AND COALESCE(u.last_seen_at, u.created_at) < NOW() - make_interval(days => $1)
+            AND NOT EXISTS (SELECT 1 FROM user_sessions s WHERE s.user_id = u.id AND s.expires_at > NOW())
+            AND NOT EXISTS (SELECT 1 FROM games g WHERE g.player1_id = u.id OR g.player2_id = u.id);",
+        days
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+    if deleted > 0 {
+        tracing::debug!("deleted {} abandoned guests", deleted);
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(per_hour: usize, trust_forwarded_for: bool) -> GuestLimiter {
+        return GuestLimiter {
+            signups: Mutex::new(HashMap::new()),
+            per_hour,
+            trust_forwarded_for,
+        };
+    }
+
+    #[test]
+    fn limits_guest_signups_per_address() {
+        let limiter = limiter(2, false);
+        let address: IpAddr = "203.0.113.7".parse().unwrap();
+        assert!(limiter.try_acquire(address));
+        assert!(limiter.try_acquire(address));
+        assert!(!limiter.try_acquire(address));
+        assert!(limiter.try_acquire("203.0.113.8".parse().unwrap()));
+    }
+
+    #[test]
+    fn only_trusts_forwarded_for_when_configured() {
+        let peer: IpAddr = "127.0.0.1".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Forwarded-For", "10.0.0.1, 203.0.113.7".parse().unwrap());
+
+        assert_eq!(limiter(1, false).client_address(peer, &headers), peer);
+        assert_eq!(
+            limiter(1, true).client_address(peer, &headers),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            limiter(1, true).client_address(peer, &HeaderMap::new()),
+            peer
+        );
+    }
+
+    fn conversion(username: &str, email: &str, password: &str) -> ConvertGuestForm {
+        return ConvertGuestForm {
+            username: username.to_string(),
+            email: email.to_string(),
+            password: password.to_string(),
+        };
+    }
+
+    #[test]
+    fn conversion_needs_a_real_username_email_and_password() {
+        assert!(validate_conversion(&conversion("alice", "alice@example.com", "hunter22")).is_ok());
+        assert!(matches!(
+            validate_conversion(&conversion("  ", "alice@example.com", "hunter22")),
+            Err(GuestError::InvalidUsername)
+        ));
+        assert!(matches!(
+            validate_conversion(&conversion("Guest-1234", "alice@example.com", "hunter22")),
+            Err(GuestError::InvalidUsername)
+        ));
+        assert!(matches!(
+            validate_conversion(&conversion("alice", " ", "hunter22")),
+            Err(GuestError::InvalidEmail)
+        ));
+        assert!(matches!(
+            validate_conversion(&conversion("alice", "alice@example.com", "short")),
+            Err(GuestError::PasswordTooShort)
+        ));
+    }
+}
@@ -0,0 +1,221 @@
+use std::{collections::HashMap, fmt};
+
+use chrono::{DateTime, Days, NaiveDate, SecondsFormat, Utc};
+use sqlx::{Pool, Postgres};
+use uuid::Uuid;
+
+use super::matchmaking_service::GameType;
+
+const DEFAULT_PAGE_SIZE: i64 = 20;
+const MAX_PAGE_SIZE: i64 = 100;
+
+// Filters for a player's game history. Everything is optional and empty values are ignored,
+// so the filter form can be submitted as is.
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct HistoryFilter {
+    pub mode: Option<String>,
+    pub opponent: Option<String>,
+    // Dates are YYYY-MM-DD and both ends are inclusive
+    pub from: Option<String>,
+    pub to: Option<String>,
+    // "win" or "loss"
+    pub result: Option<String>,
+    // Only games older than this one, for fetching the next page
+    pub before: Option<Uuid>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug)]
+pub enum HistoryError {
+    InvalidFilter(String),
+    NotFound,
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HistoryError::InvalidFilter(reason) => write!(f, "{}", reason),
+            HistoryError::NotFound => write!(f, "Player not found"),
+            HistoryError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl From<sqlx::Error> for HistoryError {
+    fn from(e: sqlx::Error) -> Self {
+        return HistoryError::Database(e);
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct HistoryRound {
+    pub number: i32,
+    pub your_throw: String,
+    pub opponent_throw: String,
+    pub result: String,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct HistoryGame {
+    pub game_id: Uuid,
+    pub game_type: String,
+    pub opponent: String,
+    pub won: bool,
+    pub your_wins: i64,
+    pub opponent_wins: i64,
+    pub finished_at: String,
+    pub rounds: Vec<HistoryRound>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct HistoryPage {
+    pub username: String,
+    pub games: Vec<HistoryGame>,
+    // Pass as `before` to get the next page, None once there are no more games
+    pub next_cursor: Option<Uuid>,
+}
+
+fn non_empty(value: &Option<String>) -> Option<&str> {
+    return value.as_deref().map(str::trim).filter(|v| !v.is_empty());
+}
+
+fn parse_date(value: &str) -> Result<DateTime<Utc>, HistoryError> {
+    return NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(|date| date.and_hms_opt(0, 0, 0).unwrap().and_utc())
+        .map_err(|_| HistoryError::InvalidFilter(format!("{} is not a YYYY-MM-DD date", value)));
+}
+
+// A page of the finished games of the player with the given username, newest first
+pub async fn get_history(
+    pool: Pool<Postgres>,
+    username: &str,
+    filter: &HistoryFilter,
+) -> Result<HistoryPage, HistoryError> {
+    let mode = non_empty(&filter.mode)
+        .map(|mode| {
+            mode.parse::<GameType>()
+                .map(|m| m.as_str())
+                .map_err(HistoryError::InvalidFilter)
+        })
+        .transpose()?;
+    let won = match non_empty(&filter.result) {
+        None => None,
+        Some("win") => Some(true),
+        Some("loss") => Some(false),
+        Some(result) => {
+            return Err(HistoryError::InvalidFilter(format!(
+                "{} is not a result, use win or loss",
+                result
+            )))
+        }
+    };
+    let from = non_empty(&filter.from).map(parse_date).transpose()?;
+    // Inclusive, so everything before the start of the next day
+    let to = non_empty(&filter.to)
+        .map(parse_date)
+        .transpose()?
+        .map(|to| to + Days::new(1));
+    let limit = filter
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+
+    let player_id = sqlx::query_scalar!("SELECT id FROM users WHERE username = $1;", username)
+        .fetch_optional(&pool)
+        .await?
+        .ok_or(HistoryError::NotFound)?;
+
+    // One extra row tells us whether there's another page
+    let mut rows = sqlx::query!(
+        r#"SELECT g.id, g.game_type, g.winner_id, g.finished_at AS "finished_at!",
+            o.username AS opponent,
+            CASE WHEN g.player1_id = $1 THEN 'player1' ELSE 'player2' END AS "side!"
+        FROM games g
+        JOIN users o ON o.id = CASE WHEN g.player1_id = $1 THEN g.player2_id ELSE g.player1_id END
+        WHERE g.status = 'finished' AND (g.player1_id = $1 OR g.player2_id = $1)
+            AND ($2::TEXT IS NULL OR g.game_type = $2)
+            AND ($3::TEXT IS NULL OR o.username = $3)
+            AND ($4::TIMESTAMPTZ IS NULL OR g.finished_at >= $4)
+            AND ($5::TIMESTAMPTZ IS NULL OR g.finished_at < $5)
+            AND ($6::BOOL IS NULL OR (g.winner_id IS NOT DISTINCT FROM $1) = $6)
+            AND ($7::UUID IS NULL
+                OR (g.finished_at, g.id) < (SELECT finished_at, id FROM games WHERE id = $7))
+        ORDER BY g.finished_at DESC, g.id DESC
+        LIMIT $8;"#,
+        player_id,
+        mode,
+        non_empty(&filter.opponent),
+        from,
+        to,
+        won,
+        filter.before,
+        limit + 1,
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    let has_more = rows.len() as i64 > limit;
+    rows.truncate(limit as usize);
+
+    let game_ids: Vec<Uuid> = rows.iter().map(|g| g.id).collect();
+    let mut rounds: HashMap<Uuid, Vec<_>> = HashMap::new();
+    for round in sqlx::query!(
+        "SELECT game_id, round_number, player1_throw, player2_throw, result
+        FROM game_rounds
+        WHERE game_id = ANY($1) AND result IS NOT NULL
+        ORDER BY game_id, round_number;",
+        &game_ids
+    )
+    .fetch_all(&pool)
+    .await?
+    {
+        rounds.entry(round.game_id).or_default().push(round);
+    }
+
+    let games: Vec<HistoryGame> = rows
+        .into_iter()
+        .map(|g| {
+            let is_player1 = g.side == "player1";
+            let rounds: Vec<HistoryRound> = rounds
+                .remove(&g.id)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|r| {
+                    let (your_throw, opponent_throw) = if is_player1 {
+                        (r.player1_throw, r.player2_throw)
+                    } else {
+                        (r.player2_throw, r.player1_throw)
+                    };
+                    let result = match r.result.as_deref() {
+                        Some("tie") => "tie",
+                        Some(side) if side == g.side => "win",
+                        _ => "loss",
+                    };
+                    return HistoryRound {
+                        number: r.round_number,
+                        your_throw: your_throw.unwrap_or_default(),
+                        opponent_throw: opponent_throw.unwrap_or_default(),
+                        result: result.to_string(),
+                    };
+                })
+                .collect();
+            return HistoryGame {
+                game_id: g.id,
+                game_type: g.game_type,
+                opponent: g.opponent,
+                won: g.winner_id == Some(player_id),
+                your_wins: rounds.iter().filter(|r| r.result == "win").count() as i64,
+                opponent_wins: rounds.iter().filter(|r| r.result == "loss").count() as i64,
+                finished_at: g.finished_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+                rounds,
+            };
+        })
+        .collect();
+
+    return Ok(HistoryPage {
+        username: username.to_string(),
+        next_cursor: games.last().filter(|_| has_more).map(|g| g.game_id),
+        games,
+    });
+}
@@ -0,0 +1,158 @@
+use std::time::Duration;
+
+use sqlx::{Pool, Postgres};
+use uuid::Uuid;
+
+use super::matchmaking_service::GameType;
+
+// How often the leaderboard view is rebuilt from the ratings table
+const DEFAULT_REFRESH_SECONDS: u64 = 60;
+// How many players are shown when no limit is asked for, and the most that can be
+const DEFAULT_TOP_LIMIT: i64 = 50;
+const MAX_TOP_LIMIT: i64 = 200;
+// How many players above and below you are shown around your own spot
+const AROUND_YOU: i64 = 5;
+
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct LeaderboardEntry {
+    // Players with the same rating share a rank
+    pub rank: i64,
+    pub username: String,
+    pub rating: i64,
+    pub games_played: i32,
+    pub is_you: bool,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct Leaderboard {
+    pub game_type: String,
+    pub players: i64,
+    pub top: Vec<LeaderboardEntry>,
+    // Your spot, or None when you haven't played a rated game of this type yet
+    pub you: Option<LeaderboardEntry>,
+    // The players just above and below you, only filled in when you're not already in the top
+    pub around_you: Vec<LeaderboardEntry>,
+    pub refreshed_at: Option<String>,
+}
+
+struct LeaderboardRow {
+    rank: i64,
+    username: String,
+    rating: f64,
+    games_played: i32,
+    user_id: Uuid,
+}
+
+impl LeaderboardRow {
+    fn into_entry(self, viewer_id: Uuid) -> LeaderboardEntry {
+        return LeaderboardEntry {
+            rank: self.rank,
+            username: self.username,
+            rating: self.rating.round() as i64,
+            games_played: self.games_played,
+            is_you: self.user_id == viewer_id,
+        };
+    }
+}
+
+// Runs forever, rebuilding the leaderboard every LEADERBOARD_REFRESH_SECONDS so requests
+// read a ready ranked snapshot instead of sorting every player's rating.
+pub async fn run_leaderboard_refresher(pool: Pool<Postgres>) {
+    let seconds = dotenv::var("LEADERBOARD_REFRESH_SECONDS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_REFRESH_SECONDS);
+    let mut interval = tokio::time::interval(Duration::from_secs(seconds));
+    loop {
+        interval.tick().await;
+        if let Err(e) = refresh_leaderboard(&pool).await {
+            tracing::error!("error refreshing leaderboard {}", e.to_string());
+        }
+    }
+}
+
+pub async fn refresh_leaderboard(pool: &Pool<Postgres>) -> Result<(), sqlx::Error> {
+    sqlx::query!("REFRESH MATERIALIZED VIEW CONCURRENTLY leaderboard;")
+        .execute(pool)
+        .await?;
+    return Ok(());
+}
+
+pub async fn get_leaderboard(
+    pool: Pool<Postgres>,
+    game_type: GameType,
+    viewer_id: Uuid,
+    limit: Option<i64>,
+) -> Result<Leaderboard, sqlx::Error> {
+    let limit = limit.unwrap_or(DEFAULT_TOP_LIMIT).clamp(1, MAX_TOP_LIMIT);
+
+    let summary = sqlx::query!(
+        r#"SELECT COUNT(*) AS "players!", MAX(refreshed_at) AS refreshed_at
+        FROM leaderboard WHERE game_type = $1;"#,
+        game_type.as_str()
+    )
+    .fetch_one(&pool)
+    .await?;
+
+    let top = sqlx::query_as!(
+        LeaderboardRow,
+        r#"SELECT rank AS "rank!", username AS "username!", rating AS "rating!",
+            games_played AS "games_played!", user_id AS "user_id!"
+        FROM leaderboard
+        WHERE game_type = $1
+        ORDER BY position
+        LIMIT $2;"#,
+        game_type.as_str(),
+        limit
+    )
+    .fetch_all(&pool)
+    .await?
+    .into_iter()
+    .map(|row| row.into_entry(viewer_id))
+    .collect::<Vec<_>>();
+
+    let position = sqlx::query_scalar!(
+        r#"SELECT position AS "position!" FROM leaderboard
+        WHERE game_type = $1 AND user_id = $2;"#,
+        game_type.as_str(),
+        viewer_id
+    )
+    .fetch_optional(&pool)
+    .await?;
+
+    let mut around_you = Vec::new();
+    if let Some(position) = position.filter(|position| *position > limit) {
+        around_you = sqlx::query_as!(
+            LeaderboardRow,
+            r#"SELECT rank AS "rank!", username AS "username!", rating AS "rating!",
+                games_played AS "games_played!", user_id AS "user_id!"
+            FROM leaderboard
+            WHERE game_type = $1 AND position BETWEEN $2 AND $3
+            ORDER BY position;"#,
+            game_type.as_str(),
+            // Don't repeat players already shown in the top
+            (position - AROUND_YOU).max(limit + 1),
+            position + AROUND_YOU
+        )
+        .fetch_all(&pool)
+        .await?
+        .into_iter()
+        .map(|row| row.into_entry(viewer_id))
+        .collect();
+    }
+
+    let you = top
+        .iter()
+        .chain(around_you.iter())
+        .find(|entry| entry.is_you)
+        .cloned();
+
+    return Ok(Leaderboard {
+        game_type: game_type.as_str().to_string(),
+        players: summary.players,
+        top,
+        you,
+        around_you,
+        refreshed_at: summary.refreshed_at.map(|at| at.to_rfc3339()),
+    });
+}
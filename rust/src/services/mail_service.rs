@@ -0,0 +1,326 @@
+use std::{fmt, path::PathBuf, sync::Arc};
+
+use handlebars::Handlebars;
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    FileTransport, Message, SmtpTransport, Transport,
+};
+
+const DEFAULT_APP_URL: &str = "http://localhost:4000";
+const DEFAULT_FROM: &str = "Roshamble <no-reply@roshamble.local>";
+const DEFAULT_MAIL_DIR: &str = "mail";
+const DEFAULT_SMTP_HOST: &str = "localhost";
+
+#[derive(Clone, Debug)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    // HTML rendered from one of the templates/emails/ templates
+    pub html: String,
+}
+
+#[derive(Debug)]
+pub struct MailError(pub String);
+
+impl fmt::Display for MailError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return write!(f, "Error sending email: {}", self.0);
+    }
+}
+
+// Sends outbound email. Sending may block, so use send_in_background from request handlers.
+pub trait Mailer: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn send(&self, email: &Email) -> Result<(), MailError>;
+}
+
+// Writes emails to the log instead of sending them, for development
+pub struct LogMailer;
+
+impl Mailer for LogMailer {
+    fn name(&self) -> &'static str {
+        return "log";
+    }
+
+    fn send(&self, email: &Email) -> Result<(), MailError> {
+        tracing::info!(
+            "email to {}\nSubject: {}\n\n{}",
+            email.to,
+            email.subject,
+            email.html
+        );
+        return Ok(());
+    }
+}
+
+// Writes each email to MAIL_DIR as an .eml file that mail clients can open
+pub struct FileMailer {
+    from: Mailbox,
+    transport: FileTransport,
+}
+
+impl Mailer for FileMailer {
+    fn name(&self) -> &'static str {
+        return "file";
+    }
+
+    fn send(&self, email: &Email) -> Result<(), MailError> {
+        let message = build_message(&self.from, email)?;
+        self.transport
+            .send(&message)
+            .map_err(|e| MailError(e.to_string()))?;
+        return Ok(());
+    }
+}
+
+// Sends through an SMTP server. SMTP_TLS picks how the connection is secured:
+// "starttls" (the default), "tls", or "none" for local stand-ins like MailHog.
+pub struct SmtpMailer {
+    from: Mailbox,
+    transport: SmtpTransport,
+}
+
+impl SmtpMailer {
+    fn from_env(from: Mailbox) -> Result<Self, MailError> {
+        let host = dotenv::var("SMTP_HOST").unwrap_or_else(|_| DEFAULT_SMTP_HOST.to_string());
+        let tls = dotenv::var("SMTP_TLS").unwrap_or_else(|_| "starttls".to_string());
+        let mut builder = match tls.to_lowercase().as_str() {
+            "starttls" => SmtpTransport::starttls_relay(&host),
+            "tls" => SmtpTransport::relay(&host),
+            "none" => Ok(SmtpTransport::builder_dangerous(&host)),
+            _ => return Err(MailError(format!("Unknown SMTP_TLS {}", tls))),
+        }
+        .map_err(|e| MailError(e.to_string()))?;
+
+        if let Some(port) = dotenv::var("SMTP_PORT").ok().and_then(|v| v.parse().ok()) {
+            builder = builder.port(port);
+        }
+        if let (Ok(username), Ok(password)) =
+            (dotenv::var("SMTP_USERNAME"), dotenv::var("SMTP_PASSWORD"))
+        {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+        return Ok(SmtpMailer {
+            from,
+            transport: builder.build(),
+        });
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn name(&self) -> &'static str {
+        return "smtp";
+    }
+
+    fn send(&self, email: &Email) -> Result<(), MailError> {
+        let message = build_message(&self.from, email)?;
+        self.transport
+            .send(&message)
+            .map_err(|e| MailError(e.to_string()))?;
+        return Ok(());
+    }
+}
+
+// The mailer picked with MAILER: "log", "file" or "smtp". Emails are sent from MAIL_FROM.
+// The log mailer writes reset and verification links into the logs, so it's only allowed,
+// and only the default, when ENVIRONMENT is development.
+pub fn mailer() -> Result<Arc<dyn Mailer>, MailError> {
+    let from = dotenv::var("MAIL_FROM")
+        .unwrap_or_else(|_| DEFAULT_FROM.to_string())
+        .parse::<Mailbox>()
+        .map_err(|e| MailError(format!("Invalid MAIL_FROM {}", e)))?;
+    let development = dotenv::var("ENVIRONMENT").is_ok_and(|env| env == "development");
+    let name = match dotenv::var("MAILER") {
+        Ok(name) => name,
+        Err(_) if development => "log".to_string(),
+        Err(_) => return Err(MailError("MAILER must be set to file or smtp".to_string())),
+    };
+    match name.to_lowercase().as_str() {
+        "log" if development => return Ok(Arc::new(LogMailer)),
+        "log" => {
+            return Err(MailError(
+                "The log mailer is only available when ENVIRONMENT is development".to_string(),
+            ))
+        }
+        "file" => {
+            let dir = PathBuf::from(
+                dotenv::var("MAIL_DIR").unwrap_or_else(|_| DEFAULT_MAIL_DIR.to_string()),
+            );
+            std::fs::create_dir_all(&dir).map_err(|e| MailError(e.to_string()))?;
+            return Ok(Arc::new(FileMailer {
+                from,
+                transport: FileTransport::new(dir),
+            }));
+        }
+        "smtp" => return Ok(Arc::new(SmtpMailer::from_env(from)?)),
+        _ => return Err(MailError(format!("Unknown MAILER {}", name))),
+    }
+}
+
+// Renders one of the templates/emails/ templates into an email
+pub fn render_email<T: serde::Serialize>(
+    templates: &Handlebars<'_>,
+    template: &str,
+    to: &str,
+    subject: &str,
+    data: &T,
+) -> Email {
+    return Email {
+        to: to.to_string(),
+        subject: subject.to_string(),
+        html: templates
+            .render(&format!("emails/{}", template), data)
+            .unwrap(),
+    };
+}
+
+// Where the site is reachable, for links in emails. Set with APP_URL.
+pub fn app_url() -> String {
+    let app_url = dotenv::var("APP_URL").unwrap_or_else(|_| DEFAULT_APP_URL.to_string());
+    return app_url.trim_end_matches('/').to_string();
+}
+
+// Sends the email on a blocking thread so the request doesn't wait on it. Failures are logged.
+pub fn send_in_background(mailer: Arc<dyn Mailer>, email: Email) {
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = mailer.send(&email) {
+            tracing::error!("{} mailer {}", mailer.name(), e.to_string());
+        }
+    });
+}
+
+fn build_message(from: &Mailbox, email: &Email) -> Result<Message, MailError> {
+    let to = email
+        .to
+        .parse::<Mailbox>()
+        .map_err(|e| MailError(format!("Invalid recipient {} {}", email.to, e)))?;
+    return Message::builder()
+        .from(from.clone())
+        .to(to)
+        .subject(email.subject.clone())
+        .header(ContentType::TEXT_HTML)
+        .body(email.html.clone())
+        .map_err(|e| MailError(e.to_string()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use handlebars::DirectorySourceOptions;
+    use serde_json::json;
+    use std::path::Path;
+    use uuid::Uuid;
+
+    // The same templates the server registers at startup
+    fn templates() -> Handlebars<'static> {
+        let mut handlebars = Handlebars::new();
+        let mut options = DirectorySourceOptions::default();
+        options.tpl_extension = ".html".to_string();
+        handlebars
+            .register_templates_directory("templates/", options)
+            .unwrap();
+        return handlebars;
+    }
+
+    fn file_mailer(dir: &Path) -> FileMailer {
+        return FileMailer {
+            from: DEFAULT_FROM.parse().unwrap(),
+            transport: FileTransport::new(dir),
+        };
+    }
+
+    fn mail_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("roshamble-mail-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        return dir;
+    }
+
+    #[test]
+    fn render_email_fills_in_the_template_inside_the_layout() {
+        let email = render_email(
+            &templates(),
+            "password_reset",
+            "alice@example.com",
+            "Reset your Roshamble password",
+            &json!({
+                "username": "alice",
+                "app_url": "http://localhost:4000",
+                "token": "abc123",
+                "minutes": 30,
+            }),
+        );
+        assert_eq!(email.to, "alice@example.com");
+        assert_eq!(email.subject, "Reset your Roshamble password");
+        assert!(email.html.starts_with("<!doctype html>"));
+        assert!(email.html.contains("Roshamble</h1>"));
+        assert!(email.html.contains("Hi alice,"));
+        assert!(email
+            .html
+            .contains("http://localhost:4000/auth/password_reset/confirm?token=abc123"));
+        assert!(email.html.contains("expires in 30 minutes"));
+    }
+
+    #[test]
+    fn render_email_escapes_player_input() {
+        let email = render_email(
+            &templates(),
+            "verify_email",
+            "alice@example.com",
+            "Verify your Roshamble email",
+            &json!({
+                "username": "<script>alert(1)</script>",
+                "app_url": "http://localhost:4000",
+                "token": "abc123",
+                "hours": 24,
+            }),
+        );
+        assert!(!email.html.contains("<script>"));
+        assert!(email.html.contains("&lt;script&gt;"));
+        assert!(email
+            .html
+            .contains("http://localhost:4000/auth/verify_email?token=abc123"));
+        assert!(email.html.contains("expires in 24 hours"));
+    }
+
+    #[test]
+    fn file_mailer_writes_an_eml_file() {
+        let dir = mail_dir();
+        let email = Email {
+            to: "alice@example.com".to_string(),
+            subject: "Hello".to_string(),
+            html: "<p>Hi alice</p>".to_string(),
+        };
+        file_mailer(&dir).send(&email).unwrap();
+
+        let files = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].extension().unwrap(), "eml");
+        let contents = std::fs::read_to_string(&files[0]).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(contents.contains("From: Roshamble <no-reply@roshamble.local>"));
+        assert!(contents.contains("To: alice@example.com"));
+        assert!(contents.contains("Subject: Hello"));
+        assert!(contents.contains("Content-Type: text/html"));
+        assert!(contents.contains("<p>Hi alice</p>"));
+    }
+
+    #[test]
+    fn file_mailer_rejects_an_invalid_recipient() {
+        let dir = mail_dir();
+        let email = Email {
+            to: "not an address".to_string(),
+            subject: "Hello".to_string(),
+            html: "<p>Hi</p>".to_string(),
+        };
+        let result = file_mailer(&dir).send(&email);
+
+        let written = std::fs::read_dir(&dir).unwrap().count();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(result.is_err());
+        assert_eq!(written, 0);
+    }
+}
@@ -1,53 +1,788 @@
-use sqlx::{Pool, Postgres};
+use std::{str::FromStr, time::Duration};
 
-use super::users_service::Claims;
+use sqlx::{PgExecutor, Pool, Postgres, Transaction};
+use uuid::Uuid;
 
+use super::{
+    events_service::{self, GameEvent},
+    game_service, rating_service,
+    users_service::Claims,
+};
+
+// How often the matchmaker scans the queues
+const MATCHMAKER_INTERVAL: Duration = Duration::from_secs(1);
+// Max number of queued players considered per scan
+const MATCHMAKER_BATCH_SIZE: i64 = 200;
+// Ranked skill window defaults, see SkillWindow
+const DEFAULT_RANKED_WINDOW_BASE: f64 = 100.0;
+const DEFAULT_RANKED_WINDOW_GROWTH: f64 = 50.0;
+const DEFAULT_RANKED_WINDOW_MAX: f64 = 1000.0;
+// How far back match metrics look by default
+const DEFAULT_METRICS_HOURS: f64 = 24.0;
+// How long both players have to show up once a match is found
+const MATCH_READY_TIMEOUT_SECONDS: f64 = 30.0;
+// Queue entries older than this are left out of the queue counts
+const DEFAULT_QUEUE_STALE_SECONDS: f64 = 600.0;
+
+#[derive(Clone, Copy)]
 pub enum GameType {
     Ranked,
     Casual,
     Tournament,
 }
 
-pub async fn add_player_to_ranked_queue(pool: Pool<Postgres>, player: Claims, gamemode: GameType) {
-    // // Add player to queue
-    // let res = sqlx::query!(
-    //     "INSERT INTO ranked_matchmaking_queue (player_id, skill_rating) VALUES ($1, $2);",
-    //     &player.id,
-    //     &player.elo,
-    // )
-    // .execute(&pool)
-    // .await;
-
-    // match res {
-    //     Ok(_) => {
-    //         println!("Player added to queue");
-    //     }
-    //     Err(e) => {
-    //         println!("Error adding player to queue: {}", e);
-    //     }
-    // }
-}
-
-pub async fn add_player_to_casual_queue(pool: Pool<Postgres>, player: Claims, gamemode: GameType) {
-    // Add player to queue
-    // let res = sqlx::query!(
-    //     "INSERT INTO ranked_matchmaking_queue (player_id, skill_rating) VALUES ($1, $2);",
-    //     &player.id,
-    //     &player.elo,
-    // )
-    // .execute(&pool)
-    // .await;
-
-    // match res {
-    //     Ok(_) => {
-    //         println!("Player added to queue");
-    //     }
-    //     Err(e) => {
-    //         println!("Error adding player to queue: {}", e);
-    //     }
-    // }
-}
-pub async fn check_player_match(player_id: String) {
-    // Check if player has a match
-    // ...
+impl GameType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GameType::Ranked => "ranked",
+            GameType::Casual => "casual",
+            GameType::Tournament => "tournament",
+        }
+    }
+
+    // Number of rounds a game of this type is played over
+    pub fn best_of(&self) -> u8 {
+        match self {
+            GameType::Ranked => 3,
+            GameType::Casual => 1,
+            GameType::Tournament => 3,
+        }
+    }
+
+    // Whether throws are committed as salted hashes before being revealed
+    pub fn uses_commit_reveal(&self) -> bool {
+        match self {
+            GameType::Ranked | GameType::Tournament => true,
+            GameType::Casual => false,
+        }
+    }
+}
+
+impl FromStr for GameType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "ranked" => Ok(GameType::Ranked),
+            "casual" => Ok(GameType::Casual),
+            "tournament" => Ok(GameType::Tournament),
+            _ => Err(format!("Unknown game type {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum QueueResult {
+    Queued,
+    AlreadyQueued,
+}
+
+pub async fn add_player_to_ranked_queue(
+    pool: Pool<Postgres>,
+    player: Claims,
+) -> Result<QueueResult, sqlx::Error> {
+    let player_id = parse_player_id(&player.id)?;
+    let mut tx = pool.begin().await?;
+
+    // A player can only wait in one queue at a time
+    sqlx::query!(
+        "DELETE FROM casual_matchmaking_queue WHERE player_id = $1;",
+        player_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    // Queue with the stored rating, the one in the claims could be out of date
+    let system = rating_service::rating_system();
+    let rating =
+        rating_service::get_full_rating(&mut *tx, system.as_ref(), player_id, GameType::Ranked)
+            .await?;
+    let res = sqlx::query!(
+        "INSERT INTO ranked_matchmaking_queue (player_id, skill_rating, rating_deviation)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (player_id) DO NOTHING;",
+        player_id,
+        rating.rating.round() as i32,
+        system.uncertainty(rating).round() as i32,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    publish_queue_updates(&mut tx).await?;
+    tx.commit().await?;
+
+    if res.rows_affected() == 0 {
+        tracing::debug!("Player {} is already queued", player.username);
+        return Ok(QueueResult::AlreadyQueued);
+    }
+    tracing::debug!("Player {} added to ranked queue", player.username);
+    return Ok(QueueResult::Queued);
+}
+
+pub async fn add_player_to_casual_queue(
+    pool: Pool<Postgres>,
+    player: Claims,
+) -> Result<QueueResult, sqlx::Error> {
+    let player_id = parse_player_id(&player.id)?;
+    let mut tx = pool.begin().await?;
+
+    // A player can only wait in one queue at a time
+    sqlx::query!(
+        "DELETE FROM ranked_matchmaking_queue WHERE player_id = $1;",
+        player_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let res = sqlx::query!(
+        "INSERT INTO casual_matchmaking_queue (player_id) VALUES ($1)
+        ON CONFLICT (player_id) DO NOTHING;",
+        player_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    publish_queue_updates(&mut tx).await?;
+    tx.commit().await?;
+
+    if res.rows_affected() == 0 {
+        tracing::debug!("Player {} is already queued", player.username);
+        return Ok(QueueResult::AlreadyQueued);
+    }
+    tracing::debug!("Player {} added to casual queue", player.username);
+    return Ok(QueueResult::Queued);
+}
+
+// Counts players waiting in the queue for a game type, ignoring stale entries.
+pub async fn count_queued_players<'e, E: PgExecutor<'e>>(
+    executor: E,
+    gamemode: GameType,
+) -> Result<i64, sqlx::Error> {
+    let stale_seconds = dotenv::var("QUEUE_STALE_SECONDS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_QUEUE_STALE_SECONDS);
+
+    let count = match gamemode {
+        GameType::Ranked => {
+            sqlx::query_scalar!(
+                r#"SELECT COUNT(*) AS "count!" FROM ranked_matchmaking_queue
+                WHERE queue_time > NOW() - make_interval(secs => $1);"#,
+                stale_seconds,
+            )
+            .fetch_one(executor)
+            .await?
+        }
+        GameType::Casual => {
+            sqlx::query_scalar!(
+                r#"SELECT COUNT(*) AS "count!" FROM casual_matchmaking_queue
+                WHERE queue_time > NOW() - make_interval(secs => $1);"#,
+                stale_seconds,
+            )
+            .fetch_one(executor)
+            .await?
+        }
+        // Tournaments don't have a queue
+        GameType::Tournament => 0,
+    };
+    return Ok(count);
+}
+
+async fn publish_queue_updates(tx: &mut Transaction<'_, Postgres>) -> Result<(), sqlx::Error> {
+    for game_type in [GameType::Ranked, GameType::Casual] {
+        let queued = count_queued_players(&mut **tx, game_type).await?;
+        events_service::publish(
+            &mut **tx,
+            &GameEvent::QueueUpdate {
+                game_type: game_type.as_str().to_string(),
+                queued,
+            },
+        )
+        .await?;
+    }
+    return Ok(());
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MatchStatus {
+    Searching,
+    MatchFound { match_id: i32, opponent: String },
+    BothReady { game_id: Uuid },
+    Expired,
+}
+
+// Looks up the player's current match and marks them as ready for it.
+pub async fn check_player_match(
+    pool: Pool<Postgres>,
+    player: Claims,
+) -> Result<MatchStatus, sqlx::Error> {
+    let player_id = parse_player_id(&player.id)?;
+    let mut tx = pool.begin().await?;
+
+    let current = sqlx::query!(
+        r#"SELECT m.match_id, m.player1_id, m.player2_id, m.player1_ready, m.player2_ready, m.status,
+            u.username AS opponent, g.id AS "game_id?",
+            m.match_time < NOW() - make_interval(secs => $2) AS "timed_out!"
+        FROM matchmaking_matches m
+        JOIN users u ON u.id = CASE WHEN m.player1_id = $1 THEN m.player2_id ELSE m.player1_id END
+        LEFT JOIN games g ON g.match_id = m.match_id
+        WHERE m.player1_id = $1 OR m.player2_id = $1
+        ORDER BY m.match_time DESC
+        LIMIT 1
+        FOR UPDATE OF m;"#,
+        player_id,
+        MATCH_READY_TIMEOUT_SECONDS,
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some(current) = current else {
+        return Ok(MatchStatus::Searching);
+    };
+
+    let status = match (current.status.as_str(), current.game_id) {
+        ("ready", Some(game_id)) => MatchStatus::BothReady { game_id },
+        ("pending", _) if current.timed_out => {
+            sqlx::query!(
+                "UPDATE matchmaking_matches SET status = 'expired' WHERE match_id = $1;",
+                current.match_id
+            )
+            .execute(&mut *tx)
+            .await?;
+            MatchStatus::Expired
+        }
+        ("pending", _) => {
+            let is_player1 = current.player1_id == Some(player_id);
+            let opponent_ready = if is_player1 {
+                current.player2_ready.unwrap_or(false)
+            } else {
+                current.player1_ready.unwrap_or(false)
+            };
+            let new_status = if opponent_ready { "ready" } else { "pending" };
+            sqlx::query!(
+                "UPDATE matchmaking_matches SET
+                    player1_ready = player1_ready OR $2,
+                    player2_ready = player2_ready OR NOT $2,
+                    status = $3
+                WHERE match_id = $1;",
+                current.match_id,
+                is_player1,
+                new_status,
+            )
+            .execute(&mut *tx)
+            .await?;
+
+            if opponent_ready {
+                let game_id = game_service::create_game(&mut tx, current.match_id).await?;
+                let (player1_id, player2_id) = match (current.player1_id, current.player2_id) {
+                    (Some(player1_id), Some(player2_id)) => (player1_id, player2_id),
+                    _ => return Err(sqlx::Error::RowNotFound),
+                };
+                events_service::publish(
+                    &mut *tx,
+                    &GameEvent::GameStarted {
+                        game_id,
+                        match_id: current.match_id,
+                        player1_id,
+                        player2_id,
+                    },
+                )
+                .await?;
+                MatchStatus::BothReady { game_id }
+            } else {
+                MatchStatus::MatchFound {
+                    match_id: current.match_id,
+                    opponent: current.opponent,
+                }
+            }
+        }
+        ("finished", _) => MatchStatus::Searching,
+        _ => {
+            // The last match expired. Anyone who queued again since then is still searching.
+            let queued = sqlx::query_scalar!(
+                r#"SELECT EXISTS (
+                    SELECT 1 FROM ranked_matchmaking_queue WHERE player_id = $1
+                    UNION ALL
+                    SELECT 1 FROM casual_matchmaking_queue WHERE player_id = $1
+                ) AS "queued!";"#,
+                player_id
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+            if queued {
+                MatchStatus::Searching
+            } else {
+                MatchStatus::Expired
+            }
+        }
+    };
+
+    tx.commit().await?;
+    return Ok(status);
+}
+
+fn parse_player_id(id: &str) -> Result<Uuid, sqlx::Error> {
+    return Uuid::parse_str(id).map_err(|e| sqlx::Error::Decode(Box::new(e)));
+}
+
+struct QueuedPlayer {
+    player_id: Uuid,
+    skill_rating: i32,
+    rating_deviation: i32,
+    wait_seconds: f64,
+}
+
+// How the ranked skill window grows with time spent in the queue
+#[derive(Clone, Copy, Debug)]
+pub enum WindowCurve {
+    Linear,
+    Quadratic,
+    Logarithmic,
+}
+
+impl FromStr for WindowCurve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "linear" => Ok(WindowCurve::Linear),
+            "quadratic" => Ok(WindowCurve::Quadratic),
+            "logarithmic" => Ok(WindowCurve::Logarithmic),
+            _ => Err(format!("Unknown window curve {}", s)),
+        }
+    }
+}
+
+// Max rating difference between two ranked opponents. Starts at `base` and widens by
+// `growth` points per minute waited, shaped by `curve`, never going past `max`.
+// Configurable with RANKED_WINDOW_BASE, RANKED_WINDOW_GROWTH, RANKED_WINDOW_CURVE and RANKED_WINDOW_MAX.
+#[derive(Clone, Copy, Debug)]
+pub struct SkillWindow {
+    pub base: f64,
+    pub growth: f64,
+    pub curve: WindowCurve,
+    pub max: f64,
+}
+
+impl SkillWindow {
+    pub fn from_env() -> Self {
+        let var = |name: &str, default: f64| {
+            dotenv::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        };
+        return SkillWindow {
+            base: var("RANKED_WINDOW_BASE", DEFAULT_RANKED_WINDOW_BASE),
+            growth: var("RANKED_WINDOW_GROWTH", DEFAULT_RANKED_WINDOW_GROWTH),
+            curve: dotenv::var("RANKED_WINDOW_CURVE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(WindowCurve::Linear),
+            max: var("RANKED_WINDOW_MAX", DEFAULT_RANKED_WINDOW_MAX),
+        };
+    }
+
+    // Window for a player who has waited `wait_seconds`, widened further by their rating deviation
+    pub fn width(&self, wait_seconds: f64, deviation: i32) -> i32 {
+        let minutes = wait_seconds.max(0.0) / 60.0;
+        let growth = match self.curve {
+            WindowCurve::Linear => minutes,
+            WindowCurve::Quadratic => minutes * minutes,
+            WindowCurve::Logarithmic => minutes.ln_1p(),
+        };
+        let width = self.base + self.growth * growth + deviation as f64;
+        return width.min(self.max).round() as i32;
+    }
+}
+
+// How long players waited for their matches and how evenly they were matched
+#[derive(Debug, serde::Serialize)]
+pub struct MatchMetrics {
+    pub game_type: String,
+    pub hours: f64,
+    pub matches: i64,
+    pub average_wait_seconds: Option<f64>,
+    pub max_wait_seconds: Option<f64>,
+    pub average_rating_gap: Option<f64>,
+    pub max_rating_gap: Option<i32>,
+}
+
+// Metrics for matches of a game type made in the last `hours` hours (24 if not given)
+pub async fn get_match_metrics(
+    pool: Pool<Postgres>,
+    game_type: GameType,
+    hours: Option<f64>,
+) -> Result<MatchMetrics, sqlx::Error> {
+    let hours = hours.unwrap_or(DEFAULT_METRICS_HOURS);
+    let row = sqlx::query!(
+        r#"SELECT COUNT(*) AS "matches!",
+            AVG((player1_wait_seconds + player2_wait_seconds) / 2) AS average_wait_seconds,
+            MAX(GREATEST(player1_wait_seconds, player2_wait_seconds)) AS max_wait_seconds,
+            AVG(rating_gap)::DOUBLE PRECISION AS average_rating_gap,
+            MAX(rating_gap) AS max_rating_gap
+        FROM matchmaking_matches
+        WHERE game_type = $1 AND match_time > NOW() - make_interval(secs => $2);"#,
+        game_type.as_str(),
+        hours * 3600.0,
+    )
+    .fetch_one(&pool)
+    .await?;
+
+    return Ok(MatchMetrics {
+        game_type: game_type.as_str().to_string(),
+        hours,
+        matches: row.matches,
+        average_wait_seconds: row.average_wait_seconds,
+        max_wait_seconds: row.max_wait_seconds,
+        average_rating_gap: row.average_rating_gap,
+        max_rating_gap: row.max_rating_gap,
+    });
+}
+
+// Runs forever, pairing queued players into matches.
+// Queue rows are locked with SKIP LOCKED so several server instances can run this at once
+// without ever pairing the same player twice.
+pub async fn run_matchmaker(pool: Pool<Postgres>) {
+    let mut interval = tokio::time::interval(MATCHMAKER_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = match_ranked_players(&pool).await {
+            tracing::error!("error matching ranked players {}", e.to_string());
+        }
+        if let Err(e) = match_casual_players(&pool).await {
+            tracing::error!("error matching casual players {}", e.to_string());
+        }
+        if let Err(e) = expire_stale_matches(&pool).await {
+            tracing::error!("error expiring matches {}", e.to_string());
+        }
+    }
+}
+
+// Expires matches that one of the players never showed up for, so clients waiting on events hear about it
+async fn expire_stale_matches(pool: &Pool<Postgres>) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let expired = sqlx::query!(
+        "UPDATE matchmaking_matches SET status = 'expired'
+        WHERE status = 'pending' AND match_time < NOW() - make_interval(secs => $1)
+        RETURNING match_id, player1_id, player2_id;",
+        MATCH_READY_TIMEOUT_SECONDS,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    for m in expired {
+        if let (Some(player1_id), Some(player2_id)) = (m.player1_id, m.player2_id) {
+            events_service::publish(
+                &mut *tx,
+                &GameEvent::MatchExpired {
+                    match_id: m.match_id,
+                    player1_id,
+                    player2_id,
+                },
+            )
+            .await?;
+        }
+    }
+
+    return tx.commit().await;
+}
+
+async fn match_ranked_players(pool: &Pool<Postgres>) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let queued = sqlx::query_as!(
+        QueuedPlayer,
+        r#"SELECT player_id, skill_rating, rating_deviation,
+            COALESCE(EXTRACT(EPOCH FROM NOW() - queue_time)::DOUBLE PRECISION, 0) AS "wait_seconds!"
+        FROM ranked_matchmaking_queue
+        ORDER BY queue_time
+        LIMIT $1
+        FOR UPDATE SKIP LOCKED;"#,
+        MATCHMAKER_BATCH_SIZE,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let pairs = pair_by_skill(&queued, &SkillWindow::from_env());
+    for (player1, player2) in &pairs {
+        create_match(
+            &mut tx,
+            player1,
+            player2,
+            Some((player1.skill_rating - player2.skill_rating).abs()),
+            GameType::Ranked,
+        )
+        .await?;
+    }
+
+    let matched: Vec<Uuid> = pairs
+        .iter()
+        .flat_map(|(a, b)| [a.player_id, b.player_id])
+        .collect();
+    sqlx::query!(
+        "DELETE FROM ranked_matchmaking_queue WHERE player_id = ANY($1);",
+        &matched
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if !pairs.is_empty() {
+        publish_queue_updates(&mut tx).await?;
+    }
+
+    return tx.commit().await;
+}
+
+async fn match_casual_players(pool: &Pool<Postgres>) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let queued = sqlx::query_as!(
+        QueuedPlayer,
+        r#"SELECT player_id, 0 AS "skill_rating!", 0 AS "rating_deviation!",
+            COALESCE(EXTRACT(EPOCH FROM NOW() - queue_time)::DOUBLE PRECISION, 0) AS "wait_seconds!"
+        FROM casual_matchmaking_queue
+        ORDER BY queue_time
+        LIMIT $1
+        FOR UPDATE SKIP LOCKED;"#,
+        MATCHMAKER_BATCH_SIZE,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    // First come, first served
+    let pairs: Vec<(&QueuedPlayer, &QueuedPlayer)> = queued
+        .chunks_exact(2)
+        .map(|pair| (&pair[0], &pair[1]))
+        .collect();
+    for (player1, player2) in &pairs {
+        create_match(&mut tx, player1, player2, None, GameType::Casual).await?;
+    }
+
+    let matched: Vec<Uuid> = pairs
+        .iter()
+        .flat_map(|(a, b)| [a.player_id, b.player_id])
+        .collect();
+    sqlx::query!(
+        "DELETE FROM casual_matchmaking_queue WHERE player_id = ANY($1);",
+        &matched
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if !pairs.is_empty() {
+        publish_queue_updates(&mut tx).await?;
+    }
+
+    return tx.commit().await;
+}
+
+// Pairs players in queue order, matching each with the closest rated player still
+// unpaired that falls inside the skill window. Each player's window grows with how long
+// they've waited and how uncertain their rating is, and a pair only needs to fit the wider one.
+fn pair_by_skill<'a>(
+    queued: &'a [QueuedPlayer],
+    window: &SkillWindow,
+) -> Vec<(&'a QueuedPlayer, &'a QueuedPlayer)> {
+    let widths: Vec<i32> = queued
+        .iter()
+        .map(|p| window.width(p.wait_seconds, p.rating_deviation))
+        .collect();
+    let mut paired = vec![false; queued.len()];
+    let mut pairs = Vec::new();
+
+    for i in 0..queued.len() {
+        if paired[i] {
+            continue;
+        }
+        let opponent = (i + 1..queued.len())
+            .filter(|&j| !paired[j])
+            .map(|j| (j, (queued[i].skill_rating - queued[j].skill_rating).abs()))
+            .filter(|&(j, gap)| gap <= widths[i].max(widths[j]))
+            .min_by_key(|&(_, gap)| gap);
+
+        if let Some((j, _)) = opponent {
+            paired[i] = true;
+            paired[j] = true;
+            pairs.push((&queued[i], &queued[j]));
+        }
+    }
+
+    return pairs;
+}
+
+async fn create_match(
+    tx: &mut Transaction<'_, Postgres>,
+    player1: &QueuedPlayer,
+    player2: &QueuedPlayer,
+    rating_gap: Option<i32>,
+    game_type: GameType,
+) -> Result<(), sqlx::Error> {
+    let match_id = sqlx::query_scalar!(
+        "INSERT INTO matchmaking_matches
+            (player1_id, player2_id, game_type, player1_wait_seconds, player2_wait_seconds, rating_gap)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING match_id;",
+        player1.player_id,
+        player2.player_id,
+        game_type.as_str(),
+        player1.wait_seconds,
+        player2.wait_seconds,
+        rating_gap,
+    )
+    .fetch_one(&mut **tx)
+    .await?;
+
+    events_service::publish(
+        &mut **tx,
+        &GameEvent::MatchFound {
+            match_id,
+            game_type: game_type.as_str().to_string(),
+            player1_id: player1.player_id,
+            player2_id: player2.player_id,
+        },
+    )
+    .await?;
+
+    tracing::debug!(
+        "Created {} match {} for {} and {}",
+        game_type.as_str(),
+        match_id,
+        player1.player_id,
+        player2.player_id
+    );
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: SkillWindow = SkillWindow {
+        base: 100.0,
+        growth: 50.0,
+        curve: WindowCurve::Linear,
+        max: 1000.0,
+    };
+
+    fn queued(id: u128, skill_rating: i32, wait_seconds: f64) -> QueuedPlayer {
+        return QueuedPlayer {
+            player_id: Uuid::from_u128(id),
+            skill_rating,
+            rating_deviation: 0,
+            wait_seconds,
+        };
+    }
+
+    fn pair_ids(pairs: &[(&QueuedPlayer, &QueuedPlayer)]) -> Vec<(Uuid, Uuid)> {
+        return pairs
+            .iter()
+            .map(|(a, b)| (a.player_id, b.player_id))
+            .collect();
+    }
+
+    #[test]
+    fn window_grows_along_its_curve_up_to_the_max() {
+        let cases = [
+            // (curve, wait seconds, rating deviation, expected width)
+            (WindowCurve::Linear, 0.0, 0, 100),
+            (WindowCurve::Linear, 120.0, 0, 200),
+            (WindowCurve::Linear, 3600.0, 0, 400),
+            (WindowCurve::Quadratic, 0.0, 0, 100),
+            (WindowCurve::Quadratic, 120.0, 0, 300),
+            (WindowCurve::Quadratic, 600.0, 0, 400),
+            (WindowCurve::Logarithmic, 0.0, 0, 100),
+            (WindowCurve::Logarithmic, 120.0, 0, 155),
+            (WindowCurve::Logarithmic, 86400.0, 0, 400),
+            // Uncertain ratings widen the window, still within the max
+            (WindowCurve::Linear, 0.0, 50, 150),
+            (WindowCurve::Linear, 120.0, 350, 400),
+            // A clock that ran backwards doesn't shrink it below the base
+            (WindowCurve::Quadratic, -60.0, 0, 100),
+        ];
+        for (curve, wait_seconds, deviation, expected) in cases {
+            let window = SkillWindow {
+                curve,
+                max: 400.0,
+                ..WINDOW
+            };
+            assert_eq!(
+                window.width(wait_seconds, deviation),
+                expected,
+                "{:?} after {}s with deviation {}",
+                curve,
+                wait_seconds,
+                deviation
+            );
+        }
+    }
+
+    #[test]
+    fn players_are_paired_in_queue_order() {
+        let queue = vec![
+            queued(1, 1500, 30.0),
+            queued(2, 1520, 20.0),
+            queued(3, 1510, 10.0),
+            queued(4, 1530, 0.0),
+        ];
+        // The longest waiting player picks first, taking the closest rating inside the window
+        assert_eq!(
+            pair_ids(&pair_by_skill(&queue, &WINDOW)),
+            vec![
+                (Uuid::from_u128(1), Uuid::from_u128(3)),
+                (Uuid::from_u128(2), Uuid::from_u128(4)),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_pair_only_needs_to_fit_the_wider_window() {
+        // 200 apart, player 1 allows 100 but player 2 has waited two minutes and allows 200
+        let queue = vec![queued(1, 1500, 0.0), queued(2, 1700, 120.0)];
+        assert_eq!(
+            pair_ids(&pair_by_skill(&queue, &WINDOW)),
+            vec![(Uuid::from_u128(1), Uuid::from_u128(2))]
+        );
+
+        // Too far apart for either window
+        let queue = vec![queued(1, 1500, 0.0), queued(2, 1800, 120.0)];
+        assert!(pair_by_skill(&queue, &WINDOW).is_empty());
+    }
+
+    #[test]
+    fn players_are_never_paired_with_themselves_or_twice() {
+        let lone = vec![queued(1, 1500, 600.0)];
+        assert!(pair_by_skill(&lone, &WINDOW).is_empty());
+
+        let queue = (1..=6)
+            .map(|id| queued(id, 1500 + id as i32, 0.0))
+            .collect::<Vec<_>>();
+        let pairs = pair_ids(&pair_by_skill(&queue, &WINDOW));
+        assert_eq!(pairs.len(), 3);
+        let mut seen = pairs
+            .iter()
+            .flat_map(|&(a, b)| {
+                assert_ne!(a, b);
+                [a, b]
+            })
+            .collect::<Vec<_>>();
+        seen.sort();
+        seen.dedup();
+        assert_eq!(seen.len(), 6);
+    }
+
+    #[test]
+    fn odd_player_out_stays_queued() {
+        let queue = vec![
+            queued(1, 1500, 0.0),
+            queued(2, 1510, 0.0),
+            queued(3, 1520, 0.0),
+        ];
+        let pairs = pair_ids(&pair_by_skill(&queue, &WINDOW));
+        assert_eq!(pairs, vec![(Uuid::from_u128(1), Uuid::from_u128(2))]);
+        assert!(pairs
+            .iter()
+            .all(|&(a, b)| a != Uuid::from_u128(3) && b != Uuid::from_u128(3)));
+    }
 }
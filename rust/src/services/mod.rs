@@ -1,2 +1,18 @@
+pub mod email_verification_service;
+pub mod events_service;
+pub mod game_service;
+pub mod guest_service;
+pub mod history_service;
+pub mod leaderboard_service;
+pub mod mail_service;
 pub mod matchmaking_service;
+pub mod oidc_service;
+pub mod password_reset_service;
+pub mod profile_service;
+pub mod rating_service;
+pub mod role_service;
+pub mod season_service;
+pub mod session_service;
+pub mod token_service;
+pub mod tournament_service;
 pub mod users_service;
@@ -0,0 +1,524 @@
+use std::{fmt, sync::LazyLock};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken as jwt;
+use sha2::{Digest, Sha256};
+use sqlx::{Pool, Postgres, Transaction};
+use uuid::Uuid;
+
+use super::{
+    mail_service,
+    token_service::{self, hash_token},
+};
+
+const DEFAULT_SCOPES: &str = "openid email profile";
+// How long a player has to finish signing in at the provider
+const LOGIN_STATE_MINUTES: i32 = 10;
+const MAX_USERNAME_LENGTH: usize = 24;
+// Signing algorithms accepted on ID tokens. The token header names the algorithm, so
+// anything not listed here (HS256 keyed with a public key, "none") is refused outright.
+const ID_TOKEN_ALGORITHMS: [(jwt::Algorithm, jwt::jwk::KeyAlgorithm); 2] = [
+    (jwt::Algorithm::RS256, jwt::jwk::KeyAlgorithm::RS256),
+    (jwt::Algorithm::ES256, jwt::jwk::KeyAlgorithm::ES256),
+];
+
+static HTTP: LazyLock<reqwest::Client> = LazyLock::new(reqwest::Client::new);
+
+#[derive(Debug)]
+pub enum OidcError {
+    UnknownProvider,
+    // The sign-in expired, was already used, or was started in another browser
+    InvalidState,
+    // The provider sent the player back with an error instead of a code
+    Denied(String),
+    // The provider's answers didn't check out
+    Provider(String),
+    Http(reqwest::Error),
+    MissingEmail,
+    // A password account already uses the email, but it isn't verified so it can't be linked
+    EmailNotLinkable,
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for OidcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OidcError::UnknownProvider => write!(f, "Unknown sign-in provider"),
+            OidcError::InvalidState => {
+                write!(f, "This sign-in has expired. Please try again.")
+            }
+            OidcError::Denied(e) => write!(f, "Sign-in was cancelled: {}", e),
+            OidcError::Provider(e) => write!(f, "Sign-in provider error: {}", e),
+            OidcError::Http(e) => write!(f, "Error contacting the sign-in provider: {}", e),
+            OidcError::MissingEmail => {
+                write!(f, "The sign-in provider didn't share an email address")
+            }
+            OidcError::EmailNotLinkable => write!(
+                f,
+                "An account already uses this email. Sign in with your password and verify your email to link it."
+            ),
+            OidcError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl From<sqlx::Error> for OidcError {
+    fn from(e: sqlx::Error) -> Self {
+        return OidcError::Database(e);
+    }
+}
+
+impl From<reqwest::Error> for OidcError {
+    fn from(e: reqwest::Error) -> Self {
+        return OidcError::Http(e);
+    }
+}
+
+// A provider from OIDC_PROVIDERS, configured with OIDC_<NAME>_ISSUER, OIDC_<NAME>_CLIENT_ID,
+// and optionally OIDC_<NAME>_CLIENT_SECRET, OIDC_<NAME>_SCOPES and OIDC_<NAME>_DISPLAY_NAME
+pub struct OidcProvider {
+    pub name: String,
+    pub display_name: String,
+    issuer: String,
+    client_id: String,
+    client_secret: Option<String>,
+    scopes: String,
+}
+
+impl OidcProvider {
+    fn from_env(name: &str) -> Option<Self> {
+        let var = |key: &str| {
+            dotenv::var(format!("OIDC_{}_{}", name.to_uppercase(), key))
+                .ok()
+                .filter(|v| !v.is_empty())
+        };
+        return Some(OidcProvider {
+            name: name.to_string(),
+            display_name: var("DISPLAY_NAME").unwrap_or(name.to_string()),
+            issuer: var("ISSUER")?.trim_end_matches('/').to_string(),
+            client_id: var("CLIENT_ID")?,
+            client_secret: var("CLIENT_SECRET"),
+            scopes: var("SCOPES").unwrap_or(DEFAULT_SCOPES.to_string()),
+        });
+    }
+
+    fn redirect_uri(&self) -> String {
+        return format!(
+            "{}/auth/oidc/{}/callback",
+            mail_service::app_url(),
+            self.name
+        );
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ProviderLink {
+    pub name: String,
+    pub display_name: String,
+}
+
+// Providers players can sign in with, for the buttons on the sign in page
+pub fn provider_links() -> Vec<ProviderLink> {
+    return configured_names()
+        .iter()
+        .filter_map(|name| OidcProvider::from_env(name))
+        .map(|provider| ProviderLink {
+            name: provider.name,
+            display_name: provider.display_name,
+        })
+        .collect();
+}
+
+pub fn provider(name: &str) -> Result<OidcProvider, OidcError> {
+    if !configured_names()
+        .iter()
+        .any(|configured| configured == name)
+    {
+        return Err(OidcError::UnknownProvider);
+    }
+    return OidcProvider::from_env(name).ok_or(OidcError::UnknownProvider);
+}
+
+fn configured_names() -> Vec<String> {
+    return dotenv::var("OIDC_PROVIDERS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|name| name.trim().to_lowercase())
+        // user_auth uses "local" for password accounts
+        .filter(|name| !name.is_empty() && name != "local")
+        .collect();
+}
+
+#[derive(serde::Deserialize)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+async fn discover(provider: &OidcProvider) -> Result<Discovery, OidcError> {
+    let discovery = HTTP
+        .get(format!(
+            "{}/.well-known/openid-configuration",
+            provider.issuer
+        ))
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<Discovery>()
+        .await?;
+    return Ok(discovery);
+}
+
+// Where to send the player to sign in, and the state to keep in their browser until they're back
+pub struct LoginRedirect {
+    pub url: String,
+    pub state: String,
+}
+
+// Starts an authorization code sign-in with PKCE, remembering the verifier and nonce
+// until the provider sends the player back
+pub async fn start_login(
+    pool: Pool<Postgres>,
+    provider: &OidcProvider,
+) -> Result<LoginRedirect, OidcError> {
+    let discovery = discover(provider).await?;
+    let state = token_service::new_token();
+    let nonce = token_service::new_token();
+    let code_verifier = token_service::new_token();
+    let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(&code_verifier));
+
+    sqlx::query!("DELETE FROM oidc_login_states WHERE expires_at <= NOW();")
+        .execute(&pool)
+        .await?;
+    sqlx::query!(
+        "INSERT INTO oidc_login_states (state_hash, provider, code_verifier, nonce, expires_at)
+        VALUES ($1, $2, $3, $4, NOW() + make_interval(mins => $5));",
+        hash_token(&state),
+        provider.name,
+        code_verifier,
+        nonce,
+        LOGIN_STATE_MINUTES
+    )
+    .execute(&pool)
+    .await?;
+
+    let url = reqwest::Url::parse_with_params(
+        &discovery.authorization_endpoint,
+        &[
+            ("response_type", "code"),
+            ("client_id", &provider.client_id),
+            ("redirect_uri", &provider.redirect_uri()),
+            ("scope", &provider.scopes),
+            ("state", &state),
+            ("nonce", &nonce),
+            ("code_challenge", &code_challenge),
+            ("code_challenge_method", "S256"),
+        ],
+    )
+    .map_err(|e| OidcError::Provider(e.to_string()))?;
+    return Ok(LoginRedirect {
+        url: url.to_string(),
+        state,
+    });
+}
+
+#[derive(serde::Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(serde::Deserialize)]
+struct IdTokenClaims {
+    sub: String,
+    nonce: Option<String>,
+    email: Option<String>,
+    email_verified: Option<bool>,
+    preferred_username: Option<String>,
+    name: Option<String>,
+}
+
+// Swaps the code the provider sent back for an ID token and finds, links or creates the
+// player's account. Returns the id of the account to sign in to.
+pub async fn finish_login(
+    pool: Pool<Postgres>,
+    provider: &OidcProvider,
+    code: &str,
+    state: &str,
+) -> Result<Uuid, OidcError> {
+    // Deleting the state up front means a code can only be redeemed once
+    let login = sqlx::query!(
+        "DELETE FROM oidc_login_states
+        WHERE state_hash = $1 AND provider = $2 AND expires_at > NOW()
+        RETURNING code_verifier, nonce;",
+        hash_token(state),
+        provider.name
+    )
+    .fetch_optional(&pool)
+    .await?
+    .ok_or(OidcError::InvalidState)?;
+
+    let discovery = discover(provider).await?;
+    let mut form = vec![
+        ("grant_type", "authorization_code".to_string()),
+        ("code", code.to_string()),
+        ("redirect_uri", provider.redirect_uri()),
+        ("client_id", provider.client_id.clone()),
+        ("code_verifier", login.code_verifier),
+    ];
+    if let Some(secret) = &provider.client_secret {
+        form.push(("client_secret", secret.clone()));
+    }
+    let response = HTTP
+        .post(&discovery.token_endpoint)
+        .form(&form)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(OidcError::Provider(format!(
+            "token request failed: {}",
+            body
+        )));
+    }
+    let tokens = response.json::<TokenResponse>().await?;
+
+    let claims = verify_id_token(provider, &discovery, &tokens.id_token).await?;
+    if claims.nonce.as_deref() != Some(login.nonce.as_str()) {
+        return Err(OidcError::Provider(
+            "ID token nonce doesn't match".to_string(),
+        ));
+    }
+
+    let mut tx = pool.begin().await?;
+    let user_id = find_or_create_user(&mut tx, provider, claims).await?;
+    tx.commit().await?;
+    return Ok(user_id);
+}
+
+// Checks the ID token's signature against the provider's published keys, and that it
+// was issued by the provider for us
+async fn verify_id_token(
+    provider: &OidcProvider,
+    discovery: &Discovery,
+    id_token: &str,
+) -> Result<IdTokenClaims, OidcError> {
+    let header = jwt::decode_header(id_token).map_err(|e| OidcError::Provider(e.to_string()))?;
+    let keys = HTTP
+        .get(&discovery.jwks_uri)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<jwt::jwk::JwkSet>()
+        .await?;
+    let jwk = match &header.kid {
+        Some(kid) => keys.find(kid),
+        None => keys.keys.first(),
+    }
+    .ok_or(OidcError::Provider(
+        "unknown ID token signing key".to_string(),
+    ))?;
+    let Some(&(algorithm, key_algorithm)) = ID_TOKEN_ALGORITHMS
+        .iter()
+        .find(|(algorithm, _)| *algorithm == header.alg)
+    else {
+        return Err(OidcError::Provider(format!(
+            "unsupported ID token algorithm {:?}",
+            header.alg
+        )));
+    };
+    // A key that declares its algorithm may only be used with that algorithm
+    if jwk
+        .common
+        .key_algorithm
+        .is_some_and(|declared| declared != key_algorithm)
+    {
+        return Err(OidcError::Provider(
+            "ID token algorithm doesn't match its signing key".to_string(),
+        ));
+    }
+    let key = jwt::DecodingKey::from_jwk(jwk).map_err(|e| OidcError::Provider(e.to_string()))?;
+
+    let mut validation = jwt::Validation::new(algorithm);
+    validation.set_audience(&[&provider.client_id]);
+    validation.set_issuer(&[&discovery.issuer]);
+    let claims = jwt::decode::<IdTokenClaims>(id_token, &key, &validation)
+        .map_err(|e| OidcError::Provider(e.to_string()))?
+        .claims;
+    return Ok(claims);
+}
+
+// Which account a sign-in belongs to
+#[derive(Debug, PartialEq)]
+enum AccountMatch {
+    // The provider account was linked on an earlier sign-in
+    Linked(Uuid),
+    // A password account with the same email, to be linked now
+    LinkExisting(Uuid),
+    Create,
+}
+
+// Decides between the account already linked to the provider's `sub`, the account
+// (id, email verified) using the same email, or a new account
+fn match_account(
+    linked: Option<Uuid>,
+    existing: Option<(Uuid, bool)>,
+    email_verified: bool,
+) -> Result<AccountMatch, OidcError> {
+    if let Some(user_id) = linked {
+        return Ok(AccountMatch::Linked(user_id));
+    }
+    match existing {
+        // Only link when both sides have proven they own the email, otherwise whoever
+        // signed up with it first could take over the other's account
+        Some((user_id, true)) if email_verified => return Ok(AccountMatch::LinkExisting(user_id)),
+        Some(_) => return Err(OidcError::EmailNotLinkable),
+        None => return Ok(AccountMatch::Create),
+    }
+}
+
+async fn find_or_create_user(
+    tx: &mut Transaction<'_, Postgres>,
+    provider: &OidcProvider,
+    claims: IdTokenClaims,
+) -> Result<Uuid, OidcError> {
+    let linked = sqlx::query_scalar!(
+        "SELECT user_id FROM user_auth WHERE auth_provider = $1 AND provider_id = $2;",
+        provider.name,
+        claims.sub
+    )
+    .fetch_optional(&mut **tx)
+    .await?;
+
+    let email_verified = claims.email_verified.unwrap_or(false);
+    let existing = match (&linked, &claims.email) {
+        (None, Some(email)) => sqlx::query!(
+            r#"SELECT id, email_verified_at IS NOT NULL AS "verified!" FROM users WHERE email = $1;"#,
+            email
+        )
+        .fetch_optional(&mut **tx)
+        .await?
+        .map(|user| (user.id, user.verified)),
+        _ => None,
+    };
+
+    let user_id = match match_account(linked, existing, email_verified)? {
+        AccountMatch::Linked(user_id) => return Ok(user_id),
+        AccountMatch::LinkExisting(user_id) => user_id,
+        AccountMatch::Create => {
+            let email = claims.email.clone().ok_or(OidcError::MissingEmail)?;
+            let username = available_username(tx, &claims, &email).await?;
+            let user_id = sqlx::query_scalar!(
+                "INSERT INTO users (username, email, email_verified_at)
+                VALUES ($1, $2, CASE WHEN $3 THEN NOW() END)
+                RETURNING id;",
+                username,
+                email,
+                email_verified
+            )
+            .fetch_one(&mut **tx)
+            .await?;
+            sqlx::query!(
+                "INSERT INTO user_roles (user_id, role) VALUES ($1, 'user');",
+                user_id
+            )
+            .execute(&mut **tx)
+            .await?;
+            user_id
+        }
+    };
+
+    sqlx::query!(
+        "INSERT INTO user_auth (user_id, auth_provider, provider_id) VALUES ($1, $2, $3);",
+        user_id,
+        provider.name,
+        claims.sub
+    )
+    .execute(&mut **tx)
+    .await?;
+    return Ok(user_id);
+}
+
+// A username for a new account based on what the provider knows the player as,
+// with a number added when it's taken
+async fn available_username(
+    tx: &mut Transaction<'_, Postgres>,
+    claims: &IdTokenClaims,
+    email: &str,
+) -> Result<String, sqlx::Error> {
+    let wanted = claims
+        .preferred_username
+        .as_deref()
+        .or(claims.name.as_deref())
+        .unwrap_or(email.split('@').next().unwrap_or_default());
+    let mut base = wanted
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-')
+        .take(MAX_USERNAME_LENGTH)
+        .collect::<String>();
+    if base.is_empty() {
+        base = "player".to_string();
+    }
+
+    let mut username = base.clone();
+    loop {
+        let taken = sqlx::query_scalar!(
+            r#"SELECT EXISTS (SELECT 1 FROM users WHERE username = $1) AS "taken!";"#,
+            username
+        )
+        .fetch_one(&mut **tx)
+        .await?;
+        if !taken {
+            return Ok(username);
+        }
+        username = format!("{}{}", base, rand::random::<u16>() % 10_000);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn links_when_both_sides_verified_the_email() {
+        let user_id = Uuid::new_v4();
+        assert_eq!(
+            match_account(None, Some((user_id, true)), true).unwrap(),
+            AccountMatch::LinkExisting(user_id)
+        );
+    }
+
+    #[test]
+    fn refuses_to_link_unverified_emails() {
+        let user_id = Uuid::new_v4();
+        for (account_verified, provider_verified) in [(false, true), (true, false), (false, false)]
+        {
+            assert!(matches!(
+                match_account(None, Some((user_id, account_verified)), provider_verified),
+                Err(OidcError::EmailNotLinkable)
+            ));
+        }
+    }
+
+    #[test]
+    fn linked_provider_accounts_sign_in_to_the_same_user() {
+        let linked = Uuid::new_v4();
+        let other = Uuid::new_v4();
+        // The email may since belong to someone else, or be unverified, the link wins
+        for existing in [None, Some((other, true)), Some((other, false))] {
+            assert_eq!(
+                match_account(Some(linked), existing, false).unwrap(),
+                AccountMatch::Linked(linked)
+            );
+        }
+    }
+
+    #[test]
+    fn creates_an_account_for_new_emails() {
+        assert_eq!(
+            match_account(None, None, false).unwrap(),
+            AccountMatch::Create
+        );
+    }
+}
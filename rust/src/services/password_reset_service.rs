@@ -0,0 +1,188 @@
+use std::fmt;
+
+use handlebars::Handlebars;
+use serde_json::json;
+use sqlx::{Pool, Postgres};
+use uuid::Uuid;
+
+use super::{
+    mail_service::{self, Email},
+    token_service::{self, hash_token},
+};
+
+const DEFAULT_TOKEN_MINUTES: i32 = 30;
+// Shortest password accepted wherever one is set
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+#[derive(Debug)]
+pub enum PasswordResetError {
+    // Unknown, expired or already used
+    InvalidToken,
+    PasswordTooShort,
+    PasswordMismatch,
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for PasswordResetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PasswordResetError::InvalidToken => {
+                write!(f, "This reset link is invalid or has expired")
+            }
+            PasswordResetError::PasswordTooShort => write!(
+                f,
+                "Passwords must be at least {} characters",
+                MIN_PASSWORD_LENGTH
+            ),
+            PasswordResetError::PasswordMismatch => write!(f, "Passwords don't match"),
+            PasswordResetError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl From<sqlx::Error> for PasswordResetError {
+    fn from(e: sqlx::Error) -> Self {
+        return PasswordResetError::Database(e);
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct ResetRequestForm {
+    pub email: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct NewPasswordForm {
+    pub token: String,
+    pub password: String,
+    pub confirm_password: String,
+}
+
+// A freshly issued token and who it's for
+pub struct ResetToken {
+    pub username: String,
+    pub email: String,
+    pub token: String,
+    // How long the token is valid for
+    pub minutes: i32,
+}
+
+// Issues a reset token for the account with this email, replacing any unused ones.
+// Returns None when no account uses the email.
+pub async fn create_reset_token(
+    pool: Pool<Postgres>,
+    email: &str,
+) -> Result<Option<ResetToken>, sqlx::Error> {
+    let user = sqlx::query!(
+        r#"SELECT id, username, email AS "email!" FROM users WHERE email = $1;"#,
+        email
+    )
+    .fetch_optional(&pool)
+    .await?;
+    let Some(user) = user else {
+        return Ok(None);
+    };
+
+    let token = token_service::new_token();
+    let minutes = dotenv::var("PASSWORD_RESET_TOKEN_MINUTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_TOKEN_MINUTES);
+
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
+        "DELETE FROM password_reset_tokens WHERE user_id = $1 AND used_at IS NULL;",
+        user.id
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "INSERT INTO password_reset_tokens (user_id, token_hash, expires_at)
+        VALUES ($1, $2, NOW() + make_interval(mins => $3));",
+        user.id,
+        hash_token(&token),
+        minutes
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    return Ok(Some(ResetToken {
+        username: user.username,
+        email: user.email,
+        token,
+        minutes,
+    }));
+}
+
+pub fn reset_email(templates: &Handlebars<'_>, reset: &ResetToken) -> Email {
+    return mail_service::render_email(
+        templates,
+        "password_reset",
+        &reset.email,
+        "Reset your Roshamble password",
+        &json!({
+            "username": reset.username,
+            "app_url": mail_service::app_url(),
+            "token": reset.token,
+            "minutes": reset.minutes,
+        }),
+    );
+}
+
+pub async fn is_valid_token(pool: Pool<Postgres>, token: &str) -> Result<bool, sqlx::Error> {
+    let valid = sqlx::query_scalar!(
+        r#"SELECT EXISTS (
+            SELECT 1 FROM password_reset_tokens
+            WHERE token_hash = $1 AND used_at IS NULL AND expires_at > NOW()
+        ) AS "valid!";"#,
+        hash_token(token)
+    )
+    .fetch_one(&pool)
+    .await?;
+    return Ok(valid);
+}
+
+// Sets a new password if the token is valid, using the token up so it can't be used again. Returns
+// the account whose password changed.
+pub async fn reset_password(
+    pool: Pool<Postgres>,
+    form: NewPasswordForm,
+) -> Result<Uuid, PasswordResetError> {
+    if form.password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(PasswordResetError::PasswordTooShort);
+    }
+    if form.password != form.confirm_password {
+        return Err(PasswordResetError::PasswordMismatch);
+    }
+
+    let mut tx = pool.begin().await?;
+    // Marking the token used in the same statement that checks it means two requests
+    // racing with the same token can't both succeed
+    let user_id = sqlx::query_scalar!(
+        "UPDATE password_reset_tokens SET used_at = NOW()
+        WHERE token_hash = $1 AND used_at IS NULL AND expires_at > NOW()
+        RETURNING user_id;",
+        hash_token(&form.token)
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(PasswordResetError::InvalidToken)?;
+
+    let hashed_password = bcrypt::hash(form.password, 12).unwrap();
+    sqlx::query!(
+        "UPDATE users SET password = $2, updated_at = NOW() WHERE id = $1;",
+        user_id,
+        hashed_password
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        "DELETE FROM password_reset_tokens WHERE user_id = $1 AND used_at IS NULL;",
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    return Ok(user_id);
+}
@@ -0,0 +1,190 @@
+use chrono::{DateTime, Utc};
+use sqlx::{Pool, Postgres};
+use uuid::Uuid;
+
+use super::{
+    matchmaking_service::GameType,
+    rating_service,
+    role_service::{self, Role},
+    season_service::{self, SeasonBadge},
+};
+
+// How many finished games show up on a profile
+const RECENT_GAMES_LIMIT: i64 = 10;
+
+#[derive(Debug, serde::Serialize)]
+pub struct ModeRating {
+    pub game_type: String,
+    pub rating: i64,
+    pub games_played: i32,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct RecentGame {
+    pub game_id: Uuid,
+    pub game_type: String,
+    pub opponent: String,
+    pub won: bool,
+    pub score: String,
+    pub finished_at: String,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct Profile {
+    pub username: String,
+    pub joined: Option<String>,
+    // Whether the profile belongs to the player looking at it
+    pub is_you: bool,
+    pub is_guest: bool,
+    pub email_verified: bool,
+    pub ratings: Vec<ModeRating>,
+    pub wins: i64,
+    pub losses: i64,
+    // Games always have a winner, but rounds can be drawn
+    pub round_wins: i64,
+    pub round_losses: i64,
+    pub round_draws: i64,
+    pub favorite_throw: Option<String>,
+    pub recent_games: Vec<RecentGame>,
+    // Top finishes in past seasons
+    pub badges: Vec<SeasonBadge>,
+    // Whether the player looking at it can change the profile's roles
+    pub can_manage_roles: bool,
+}
+
+// Public profile of the player with the given username, or None if there's no such player
+pub async fn get_profile(
+    pool: Pool<Postgres>,
+    username: &str,
+    viewer_id: Uuid,
+) -> Result<Option<Profile>, sqlx::Error> {
+    let user = sqlx::query!(
+        r#"SELECT id, username, created_at, email_verified_at IS NOT NULL AS "email_verified!"
+        FROM users WHERE username = $1;"#,
+        username
+    )
+    .fetch_optional(&pool)
+    .await?;
+    let Some(user) = user else {
+        return Ok(None);
+    };
+
+    let stored_ratings = sqlx::query!(
+        "SELECT game_type, rating, games_played FROM ratings WHERE user_id = $1;",
+        user.id
+    )
+    .fetch_all(&pool)
+    .await?;
+    let ratings = [GameType::Ranked, GameType::Casual, GameType::Tournament]
+        .iter()
+        .map(|game_type| {
+            let stored = stored_ratings
+                .iter()
+                .find(|r| r.game_type == game_type.as_str());
+            return ModeRating {
+                game_type: game_type.as_str().to_string(),
+                rating: stored
+                    .map(|r| r.rating)
+                    .unwrap_or(rating_service::DEFAULT_RATING)
+                    .round() as i64,
+                games_played: stored.map(|r| r.games_played).unwrap_or(0),
+            };
+        })
+        .collect();
+
+    let record = sqlx::query!(
+        r#"SELECT COUNT(*) FILTER (WHERE winner_id = $1) AS "wins!",
+            COUNT(*) FILTER (WHERE winner_id IS DISTINCT FROM $1) AS "losses!"
+        FROM games
+        WHERE status = 'finished' AND (player1_id = $1 OR player2_id = $1);"#,
+        user.id
+    )
+    .fetch_one(&pool)
+    .await?;
+
+    let rounds = sqlx::query!(
+        r#"SELECT COUNT(*) FILTER (WHERE r.result = CASE WHEN g.player1_id = $1 THEN 'player1' ELSE 'player2' END) AS "wins!",
+            COUNT(*) FILTER (WHERE r.result = CASE WHEN g.player1_id = $1 THEN 'player2' ELSE 'player1' END) AS "losses!",
+            COUNT(*) FILTER (WHERE r.result = 'tie') AS "draws!"
+        FROM game_rounds r
+        JOIN games g ON g.id = r.game_id
+        WHERE g.player1_id = $1 OR g.player2_id = $1;"#,
+        user.id
+    )
+    .fetch_one(&pool)
+    .await?;
+
+    let favorite_throw = sqlx::query_scalar!(
+        r#"SELECT CASE WHEN g.player1_id = $1 THEN r.player1_throw ELSE r.player2_throw END AS "throw!"
+        FROM game_rounds r
+        JOIN games g ON g.id = r.game_id
+        WHERE (g.player1_id = $1 OR g.player2_id = $1) AND r.result IS NOT NULL
+        GROUP BY 1
+        ORDER BY COUNT(*) DESC, 1
+        LIMIT 1;"#,
+        user.id
+    )
+    .fetch_optional(&pool)
+    .await?;
+
+    let recent_games = sqlx::query!(
+        r#"SELECT g.id, g.game_type, g.winner_id, g.finished_at AS "finished_at!",
+            o.username AS opponent,
+            (SELECT COUNT(*) FROM game_rounds r
+                WHERE r.game_id = g.id
+                AND r.result = CASE WHEN g.player1_id = $1 THEN 'player1' ELSE 'player2' END) AS "your_wins!",
+            (SELECT COUNT(*) FROM game_rounds r
+                WHERE r.game_id = g.id
+                AND r.result = CASE WHEN g.player1_id = $1 THEN 'player2' ELSE 'player1' END) AS "opponent_wins!"
+        FROM games g
+        JOIN users o ON o.id = CASE WHEN g.player1_id = $1 THEN g.player2_id ELSE g.player1_id END
+        WHERE g.status = 'finished' AND (g.player1_id = $1 OR g.player2_id = $1)
+        ORDER BY g.finished_at DESC
+        LIMIT $2;"#,
+        user.id,
+        RECENT_GAMES_LIMIT,
+    )
+    .fetch_all(&pool)
+    .await?
+    .into_iter()
+    .map(|g| RecentGame {
+        game_id: g.id,
+        game_type: g.game_type,
+        opponent: g.opponent,
+        won: g.winner_id == Some(user.id),
+        score: format!("{} - {}", g.your_wins, g.opponent_wins),
+        finished_at: format_date(g.finished_at),
+    })
+    .collect();
+
+    let badges = season_service::get_badges(&pool, user.id).await?;
+    let is_guest = role_service::get_roles(&pool, user.id)
+        .await?
+        .contains(&Role::Guest);
+    let can_manage_roles = role_service::get_roles(&pool, viewer_id)
+        .await?
+        .iter()
+        .any(|role| role.grants(Role::Admin));
+
+    return Ok(Some(Profile {
+        username: user.username,
+        joined: user.created_at.map(format_date),
+        is_you: user.id == viewer_id,
+        is_guest,
+        email_verified: user.email_verified,
+        ratings,
+        wins: record.wins,
+        losses: record.losses,
+        round_wins: rounds.wins,
+        round_losses: rounds.losses,
+        round_draws: rounds.draws,
+        favorite_throw,
+        recent_games,
+        badges,
+        can_manage_roles,
+    }));
+}
+
+fn format_date(time: DateTime<Utc>) -> String {
+    return time.format("%Y-%m-%d").to_string();
+}
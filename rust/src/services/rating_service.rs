@@ -0,0 +1,443 @@
+use std::f64::consts::PI;
+
+use sqlx::{PgExecutor, Postgres, Transaction};
+use uuid::Uuid;
+
+use super::matchmaking_service::GameType;
+
+// Rating every player starts out with
+pub const DEFAULT_RATING: f64 = 1000.0;
+// Glicko-2 deviation and volatility every player starts out with
+pub const DEFAULT_DEVIATION: f64 = 350.0;
+pub const DEFAULT_VOLATILITY: f64 = 0.06;
+const DEFAULT_K_FACTOR: f64 = 32.0;
+const DEFAULT_GLICKO2_TAU: f64 = 0.5;
+// Converts between the Glicko and Glicko-2 scales
+const GLICKO2_SCALE: f64 = 173.7178;
+// Convergence tolerance when solving for the new volatility
+const GLICKO2_EPSILON: f64 = 0.000001;
+// Length of a Glicko-2 rating period. Deviation grows for every period a player sits out.
+const RATING_PERIOD_DAYS: f64 = 1.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rating {
+    pub rating: f64,
+    pub deviation: f64,
+    pub volatility: f64,
+}
+
+impl Default for Rating {
+    fn default() -> Self {
+        Rating {
+            rating: DEFAULT_RATING,
+            deviation: DEFAULT_DEVIATION,
+            volatility: DEFAULT_VOLATILITY,
+        }
+    }
+}
+
+// A way of updating player ratings after games
+pub trait RatingSystem: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // New rating after a game against `opponent`. `score` is 1 for a win, 0.5 for a draw and 0 for a loss.
+    fn rate(&self, player: Rating, opponent: Rating, score: f64) -> Rating;
+
+    // Rating after sitting out `periods` rating periods
+    fn decay(&self, player: Rating, _periods: f64) -> Rating {
+        return player;
+    }
+
+    // How unsure the system is about a rating, in rating points. The matchmaker
+    // widens the search window by this much.
+    fn uncertainty(&self, _player: Rating) -> f64 {
+        return 0.0;
+    }
+}
+
+pub struct Elo {
+    pub k_factor: f64,
+}
+
+impl RatingSystem for Elo {
+    fn name(&self) -> &'static str {
+        return "elo";
+    }
+
+    // Elo only tracks the rating, deviation and volatility are left alone
+    fn rate(&self, player: Rating, opponent: Rating, score: f64) -> Rating {
+        return Rating {
+            rating: elo_update(player.rating, opponent.rating, score, self.k_factor),
+            ..player
+        };
+    }
+}
+
+// Glicko-2 as described in http://www.glicko.net/glicko/glicko2.pdf, treating every
+// game as its own rating period. The scale is centred on DEFAULT_RATING instead of 1500.
+pub struct Glicko2 {
+    // Constrains how quickly volatility can change
+    pub tau: f64,
+}
+
+impl Glicko2 {
+    fn g(phi: f64) -> f64 {
+        return 1.0 / (1.0 + 3.0 * phi * phi / (PI * PI)).sqrt();
+    }
+
+    fn new_volatility(&self, phi: f64, sigma: f64, v: f64, delta: f64) -> f64 {
+        let a = (sigma * sigma).ln();
+        let tau = self.tau;
+        let f = |x: f64| {
+            let ex = x.exp();
+            let d = phi * phi + v + ex;
+            return ex * (delta * delta - phi * phi - v - ex) / (2.0 * d * d)
+                - (x - a) / (tau * tau);
+        };
+
+        let mut big_a = a;
+        let mut big_b = if delta * delta > phi * phi + v {
+            (delta * delta - phi * phi - v).ln()
+        } else {
+            let mut k = 1.0;
+            while f(a - k * tau) < 0.0 {
+                k += 1.0;
+            }
+            a - k * tau
+        };
+
+        let mut f_a = f(big_a);
+        let mut f_b = f(big_b);
+        while (big_b - big_a).abs() > GLICKO2_EPSILON {
+            let big_c = big_a + (big_a - big_b) * f_a / (f_b - f_a);
+            let f_c = f(big_c);
+            if f_c * f_b <= 0.0 {
+                big_a = big_b;
+                f_a = f_b;
+            } else {
+                f_a /= 2.0;
+            }
+            big_b = big_c;
+            f_b = f_c;
+        }
+
+        return (big_a / 2.0).exp();
+    }
+
+    // New rating after all of a rating period's games, each an opponent and the score against them
+    fn rate_period(&self, player: Rating, games: &[(Rating, f64)]) -> Rating {
+        let mu = (player.rating - DEFAULT_RATING) / GLICKO2_SCALE;
+        let phi = player.deviation / GLICKO2_SCALE;
+
+        let mut v_inverse = 0.0;
+        let mut improvement = 0.0;
+        for (opponent, score) in games {
+            let mu_j = (opponent.rating - DEFAULT_RATING) / GLICKO2_SCALE;
+            let g = Glicko2::g(opponent.deviation / GLICKO2_SCALE);
+            let expected = 1.0 / (1.0 + (-g * (mu - mu_j)).exp());
+            v_inverse += g * g * expected * (1.0 - expected);
+            improvement += g * (score - expected);
+        }
+        let v = 1.0 / v_inverse;
+        let delta = v * improvement;
+
+        let volatility = self.new_volatility(phi, player.volatility, v, delta);
+        let phi_star = (phi * phi + volatility * volatility).sqrt();
+        let new_phi = 1.0 / (1.0 / (phi_star * phi_star) + 1.0 / v).sqrt();
+        let new_mu = mu + new_phi * new_phi * improvement;
+
+        return Rating {
+            rating: new_mu * GLICKO2_SCALE + DEFAULT_RATING,
+            deviation: (new_phi * GLICKO2_SCALE).min(DEFAULT_DEVIATION),
+            volatility,
+        };
+    }
+}
+
+impl RatingSystem for Glicko2 {
+    fn name(&self) -> &'static str {
+        return "glicko2";
+    }
+
+    fn rate(&self, player: Rating, opponent: Rating, score: f64) -> Rating {
+        return self.rate_period(player, &[(opponent, score)]);
+    }
+
+    fn decay(&self, player: Rating, periods: f64) -> Rating {
+        let phi = player.deviation / GLICKO2_SCALE;
+        let phi = (phi * phi + periods * player.volatility * player.volatility).sqrt();
+        return Rating {
+            deviation: (phi * GLICKO2_SCALE).min(DEFAULT_DEVIATION),
+            ..player
+        };
+    }
+
+    fn uncertainty(&self, player: Rating) -> f64 {
+        return player.deviation;
+    }
+}
+
+// The rating system picked with RATING_SYSTEM, either "elo" (the default) or "glicko2"
+pub fn rating_system() -> Box<dyn RatingSystem> {
+    let system = dotenv::var("RATING_SYSTEM").unwrap_or_default();
+    match system.to_lowercase().as_str() {
+        "glicko2" | "glicko-2" => {
+            let tau = dotenv::var("GLICKO2_TAU")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_GLICKO2_TAU);
+            return Box::new(Glicko2 { tau });
+        }
+        "elo" | "" => {}
+        _ => tracing::warn!("Unknown RATING_SYSTEM {}, using elo", system),
+    }
+    return Box::new(Elo {
+        k_factor: k_factor(),
+    });
+}
+
+// How far a single game can move an Elo rating. Configurable with ELO_K_FACTOR.
+pub fn k_factor() -> f64 {
+    return dotenv::var("ELO_K_FACTOR")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_K_FACTOR);
+}
+
+// Probability of a player rated `rating` beating one rated `opponent`
+pub fn expected_score(rating: f64, opponent: f64) -> f64 {
+    return 1.0 / (1.0 + 10f64.powf((opponent - rating) / 400.0));
+}
+
+// New rating after a game. `score` is 1 for a win, 0.5 for a draw and 0 for a loss.
+pub fn elo_update(rating: f64, opponent: f64, score: f64, k: f64) -> f64 {
+    return rating + k * (score - expected_score(rating, opponent));
+}
+
+pub async fn get_rating<'e, E: PgExecutor<'e>>(
+    executor: E,
+    user_id: Uuid,
+    game_type: GameType,
+) -> Result<f64, sqlx::Error> {
+    let rating = sqlx::query_scalar!(
+        "SELECT rating FROM ratings WHERE user_id = $1 AND game_type = $2;",
+        user_id,
+        game_type.as_str(),
+    )
+    .fetch_optional(executor)
+    .await?;
+    return Ok(rating.unwrap_or(DEFAULT_RATING));
+}
+
+// A player's full rating, with deviation grown for the time they've been inactive
+pub async fn get_full_rating<'e, E: PgExecutor<'e>>(
+    executor: E,
+    system: &dyn RatingSystem,
+    user_id: Uuid,
+    game_type: GameType,
+) -> Result<Rating, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"SELECT rating, deviation, volatility,
+            EXTRACT(EPOCH FROM NOW() - updated_at)::DOUBLE PRECISION / 86400 AS "days_inactive!"
+        FROM ratings WHERE user_id = $1 AND game_type = $2;"#,
+        user_id,
+        game_type.as_str(),
+    )
+    .fetch_optional(executor)
+    .await?;
+
+    return Ok(match row {
+        Some(r) => system.decay(
+            Rating {
+                rating: r.rating,
+                deviation: r.deviation,
+                volatility: r.volatility,
+            },
+            (r.days_inactive / RATING_PERIOD_DAYS).floor(),
+        ),
+        None => Rating::default(),
+    });
+}
+
+// Updates both players' ratings for a finished game, inside the transaction that finishes it.
+pub async fn apply_game_result(
+    tx: &mut Transaction<'_, Postgres>,
+    game_type: GameType,
+    winner_id: Uuid,
+    loser_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO ratings (user_id, game_type, rating, deviation, volatility)
+        VALUES ($1, $3, $4, $5, $6), ($2, $3, $4, $5, $6)
+        ON CONFLICT (user_id, game_type) DO NOTHING;",
+        winner_id,
+        loser_id,
+        game_type.as_str(),
+        DEFAULT_RATING,
+        DEFAULT_DEVIATION,
+        DEFAULT_VOLATILITY,
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    // Lock in a consistent order so two games finishing at once can't deadlock
+    let rows = sqlx::query!(
+        r#"SELECT user_id, rating, deviation, volatility,
+            EXTRACT(EPOCH FROM NOW() - updated_at)::DOUBLE PRECISION / 86400 AS "days_inactive!"
+        FROM ratings
+        WHERE game_type = $1 AND user_id IN ($2, $3)
+        ORDER BY user_id
+        FOR UPDATE;"#,
+        game_type.as_str(),
+        winner_id,
+        loser_id,
+    )
+    .fetch_all(&mut **tx)
+    .await?;
+
+    let system = rating_system();
+    let rating_of = |id: Uuid| {
+        rows.iter()
+            .find(|r| r.user_id == id)
+            .map(|r| {
+                system.decay(
+                    Rating {
+                        rating: r.rating,
+                        deviation: r.deviation,
+                        volatility: r.volatility,
+                    },
+                    (r.days_inactive / RATING_PERIOD_DAYS).floor(),
+                )
+            })
+            .unwrap_or_default()
+    };
+    let winner_rating = rating_of(winner_id);
+    let loser_rating = rating_of(loser_id);
+
+    let new_winner_rating = system.rate(winner_rating, loser_rating, 1.0);
+    let new_loser_rating = system.rate(loser_rating, winner_rating, 0.0);
+
+    for (user_id, rating) in [(winner_id, new_winner_rating), (loser_id, new_loser_rating)] {
+        sqlx::query!(
+            "UPDATE ratings
+            SET rating = $3, deviation = $4, volatility = $5,
+                games_played = games_played + 1, season_games = season_games + 1,
+                updated_at = NOW()
+            WHERE user_id = $1 AND game_type = $2;",
+            user_id,
+            game_type.as_str(),
+            rating.rating,
+            rating.deviation,
+            rating.volatility,
+        )
+        .execute(&mut **tx)
+        .await?;
+    }
+
+    tracing::debug!(
+        "{} {} rating {:.0} -> {:.0}, {} rating {:.0} -> {:.0}",
+        system.name(),
+        winner_id,
+        winner_rating.rating,
+        new_winner_rating.rating,
+        loser_id,
+        loser_rating.rating,
+        new_loser_rating.rating
+    );
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Glicko-2 values are checked against the worked example in Glickman's paper, shifted
+    // by 500 points because our scale is centred on 1000 rather than 1500
+    const PAPER_OFFSET: f64 = 1500.0 - DEFAULT_RATING;
+
+    fn rating(rating: f64, deviation: f64) -> Rating {
+        return Rating {
+            rating: rating - PAPER_OFFSET,
+            deviation,
+            volatility: DEFAULT_VOLATILITY,
+        };
+    }
+
+    #[test]
+    fn glicko2_matches_the_paper_example() {
+        let system = Glicko2 { tau: 0.5 };
+        let updated = system.rate_period(
+            rating(1500.0, 200.0),
+            &[
+                (rating(1400.0, 30.0), 1.0),
+                (rating(1550.0, 100.0), 0.0),
+                (rating(1700.0, 300.0), 0.0),
+            ],
+        );
+        assert!(
+            (updated.rating + PAPER_OFFSET - 1464.06).abs() < 0.01,
+            "{:?}",
+            updated
+        );
+        assert!((updated.deviation - 151.52).abs() < 0.01, "{:?}", updated);
+        assert!(
+            (updated.volatility - 0.05999).abs() < 0.00001,
+            "{:?}",
+            updated
+        );
+    }
+
+    #[test]
+    fn glicko2_volatility_iteration_matches_the_paper_example() {
+        let system = Glicko2 { tau: 0.5 };
+        let volatility = system.new_volatility(1.1513, 0.06, 1.7785, -0.4834);
+        assert!((volatility - 0.05999).abs() < 0.00001, "{}", volatility);
+    }
+
+    #[test]
+    fn glicko2_deviation_grows_while_inactive() {
+        let system = Glicko2 { tau: 0.5 };
+        let player = rating(1500.0, 200.0);
+        assert_eq!(system.decay(player, 0.0), player);
+
+        let one_day = system.decay(player, 1.0);
+        let phi = 200.0 / GLICKO2_SCALE;
+        let expected = (phi * phi + 0.06 * 0.06).sqrt() * GLICKO2_SCALE;
+        assert!((one_day.deviation - expected).abs() < 1e-9, "{:?}", one_day);
+        assert_eq!(one_day.rating, player.rating);
+
+        let thirty_days = system.decay(player, 30.0);
+        assert!(thirty_days.deviation > one_day.deviation);
+        assert_eq!(system.decay(player, 100_000.0).deviation, DEFAULT_DEVIATION);
+    }
+
+    #[test]
+    fn elo_is_symmetric_and_zero_sum() {
+        let elo = Elo { k_factor: 32.0 };
+        for (a, b) in [(1000.0, 1000.0), (1200.0, 950.0), (800.0, 1400.0)] {
+            assert!((expected_score(a, b) + expected_score(b, a) - 1.0).abs() < 1e-12);
+            for score in [0.0, 0.5, 1.0] {
+                let player = elo.rate(rating(a, 350.0), rating(b, 350.0), score);
+                let opponent = elo.rate(rating(b, 350.0), rating(a, 350.0), 1.0 - score);
+                let gained = player.rating - (a - PAPER_OFFSET);
+                let lost = (b - PAPER_OFFSET) - opponent.rating;
+                assert!(
+                    (gained - lost).abs() < 1e-9,
+                    "{} vs {} scoring {}",
+                    a,
+                    b,
+                    score
+                );
+            }
+        }
+        assert_eq!(elo_update(1000.0, 1000.0, 1.0, 32.0), 1016.0);
+    }
+
+    #[test]
+    fn elo_ignores_inactivity() {
+        let elo = Elo { k_factor: 32.0 };
+        let player = rating(1500.0, 200.0);
+        assert_eq!(elo.decay(player, 30.0), player);
+        assert_eq!(elo.uncertainty(player), 0.0);
+    }
+}
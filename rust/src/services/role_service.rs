@@ -0,0 +1,175 @@
+use std::{fmt, str::FromStr};
+
+use sqlx::{PgExecutor, Pool, Postgres};
+use uuid::Uuid;
+
+// What a player is allowed to do, from the user_roles table. Everyone who signs up is a User.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Admin,
+    Moderator,
+    User,
+    Guest,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Moderator => "moderator",
+            Role::User => "user",
+            Role::Guest => "guest",
+        }
+    }
+
+    // Whether holding this role is enough for something that needs `required`.
+    // Admins can do anything moderators can.
+    pub fn grants(&self, required: Role) -> bool {
+        return *self == required || (*self == Role::Admin && required == Role::Moderator);
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "admin" => Ok(Role::Admin),
+            "moderator" => Ok(Role::Moderator),
+            "user" => Ok(Role::User),
+            "guest" => Ok(Role::Guest),
+            _ => Err(format!("Unknown role {}", s)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RoleError {
+    UserNotFound,
+    // Taking the role away would leave nobody able to hand out roles
+    LastAdmin,
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for RoleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoleError::UserNotFound => write!(f, "Player not found"),
+            RoleError::LastAdmin => write!(f, "There has to be at least one admin"),
+            RoleError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl From<sqlx::Error> for RoleError {
+    fn from(e: sqlx::Error) -> Self {
+        return RoleError::Database(e);
+    }
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct UserRoles {
+    pub username: String,
+    pub roles: Vec<Role>,
+}
+
+pub async fn get_roles<'e, E: PgExecutor<'e>>(
+    executor: E,
+    user_id: Uuid,
+) -> Result<Vec<Role>, sqlx::Error> {
+    let roles = sqlx::query_scalar!(
+        "SELECT role FROM user_roles WHERE user_id = $1 ORDER BY assigned_at, role;",
+        user_id
+    )
+    .fetch_all(executor)
+    .await?
+    .iter()
+    .filter_map(|role| role.parse().ok())
+    .collect();
+    return Ok(roles);
+}
+
+pub async fn get_user_roles(pool: Pool<Postgres>, username: &str) -> Result<UserRoles, RoleError> {
+    let user_id = find_user(&pool, username).await?;
+    return Ok(UserRoles {
+        username: username.to_string(),
+        roles: get_roles(&pool, user_id).await?,
+    });
+}
+
+pub async fn grant_role(
+    pool: Pool<Postgres>,
+    username: &str,
+    role: Role,
+) -> Result<UserRoles, RoleError> {
+    let user_id = find_user(&pool, username).await?;
+    sqlx::query!(
+        "INSERT INTO user_roles (user_id, role) VALUES ($1, $2) ON CONFLICT DO NOTHING;",
+        user_id,
+        role.as_str()
+    )
+    .execute(&pool)
+    .await?;
+    return get_user_roles(pool, username).await;
+}
+
+pub async fn revoke_role(
+    pool: Pool<Postgres>,
+    username: &str,
+    role: Role,
+) -> Result<UserRoles, RoleError> {
+    let user_id = find_user(&pool, username).await?;
+    let mut tx = pool.begin().await?;
+    if role == Role::Admin {
+        // Lock the admins so two of them can't demote each other at the same time
+        let admins =
+            sqlx::query_scalar!("SELECT user_id FROM user_roles WHERE role = 'admin' FOR UPDATE;")
+                .fetch_all(&mut *tx)
+                .await?;
+        if admins.iter().all(|admin| *admin == user_id) {
+            return Err(RoleError::LastAdmin);
+        }
+    }
+    sqlx::query!(
+        "DELETE FROM user_roles WHERE user_id = $1 AND role = $2;",
+        user_id,
+        role.as_str()
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    return get_user_roles(pool, username).await;
+}
+
+// Makes the accounts listed in ADMIN_EMAILS admins, so a fresh install has someone
+// who can hand out roles. Only verified emails count, otherwise anyone could sign up
+// with one of them first.
+pub async fn grant_admins_from_env(pool: &Pool<Postgres>) -> Result<(), sqlx::Error> {
+    let emails = dotenv::var("ADMIN_EMAILS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|email| email.trim().to_string())
+        .filter(|email| !email.is_empty())
+        .collect::<Vec<_>>();
+    if emails.is_empty() {
+        return Ok(());
+    }
+    sqlx::query!(
+        "INSERT INTO user_roles (user_id, role)
+        SELECT id, 'admin' FROM users
+        WHERE email = ANY($1) AND email_verified_at IS NOT NULL
+        ON CONFLICT DO NOTHING;",
+        &emails
+    )
+    .execute(pool)
+    .await?;
+    return Ok(());
+}
+
+async fn find_user(pool: &Pool<Postgres>, username: &str) -> Result<Uuid, RoleError> {
+    return sqlx::query_scalar!("SELECT id FROM users WHERE username = $1;", username)
+        .fetch_optional(pool)
+        .await?
+        .ok_or(RoleError::UserNotFound);
+}
@@ -0,0 +1,278 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use sqlx::{PgExecutor, Pool, Postgres, Transaction};
+use uuid::Uuid;
+
+use super::{leaderboard_service, matchmaking_service::GameType, rating_service};
+
+// How often to check whether the current season is over
+const SEASON_SCHEDULER_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_SEASON_LENGTH_DAYS: i32 = 90;
+// How far ratings are pulled back toward the mean when a season ends, from 0 (not at all) to 1 (fully)
+const DEFAULT_RESET_FACTOR: f64 = 0.5;
+
+#[derive(Debug, serde::Serialize)]
+pub struct SeasonSummary {
+    pub id: i32,
+    pub name: String,
+    pub starts_at: String,
+    pub ends_at: String,
+    pub status: String,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct SeasonStanding {
+    pub rank: i32,
+    pub username: String,
+    pub rating: i64,
+    pub games_played: i32,
+    pub badge: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct SeasonStandingsView {
+    pub season: SeasonSummary,
+    pub game_type: String,
+    // Empty until the season is archived
+    pub standings: Vec<SeasonStanding>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct SeasonBadge {
+    pub season_id: i32,
+    pub season: String,
+    pub game_type: String,
+    pub rank: i32,
+    pub badge: String,
+}
+
+// Where ratings are pulled toward at the end of a season, and how far
+struct SoftReset {
+    mean: f64,
+    factor: f64,
+}
+
+impl SoftReset {
+    fn from_env() -> Self {
+        return SoftReset {
+            mean: dotenv::var("SEASON_RESET_MEAN")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(rating_service::DEFAULT_RATING),
+            factor: dotenv::var("SEASON_RESET_FACTOR")
+                .ok()
+                .and_then(|v| v.parse::<f64>().ok())
+                .unwrap_or(DEFAULT_RESET_FACTOR)
+                .clamp(0.0, 1.0),
+        };
+    }
+}
+
+// Runs forever, archiving the current season once it's over and starting the next one.
+pub async fn run_season_scheduler(pool: Pool<Postgres>) {
+    let mut interval = tokio::time::interval(SEASON_SCHEDULER_INTERVAL);
+    loop {
+        interval.tick().await;
+        match roll_over_season(&pool).await {
+            Ok(true) => {
+                if let Err(e) = leaderboard_service::refresh_leaderboard(&pool).await {
+                    tracing::error!("error refreshing leaderboard {}", e.to_string());
+                }
+            }
+            Ok(false) => {}
+            Err(e) => tracing::error!("error rolling over season {}", e.to_string()),
+        }
+    }
+}
+
+// Starts a season when none is running and archives the running one once it has ended.
+// Returns whether a season was archived, which means ratings were reset.
+async fn roll_over_season(pool: &Pool<Postgres>) -> Result<bool, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let active = sqlx::query!(
+        r#"SELECT id, name, ends_at <= NOW() AS "ended!"
+        FROM seasons WHERE status = 'active'
+        FOR UPDATE;"#
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let archived = match active {
+        None => false,
+        Some(season) if !season.ended => return Ok(false),
+        Some(season) => {
+            archive_season(&mut tx, season.id).await?;
+            tracing::debug!("archived {}", season.name);
+            true
+        }
+    };
+
+    let length_days = dotenv::var("SEASON_LENGTH_DAYS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_SEASON_LENGTH_DAYS);
+    // Another instance may have started one in the meantime
+    sqlx::query!(
+        "INSERT INTO seasons (name, starts_at, ends_at)
+        SELECT 'Season ' || (COUNT(*) + 1), NOW(), NOW() + make_interval(days => $1)
+        FROM seasons
+        ON CONFLICT (status) WHERE status = 'active' DO NOTHING;",
+        length_days
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    return Ok(archived);
+}
+
+// Snapshots the final standings of everyone who played this season, then soft resets ratings
+async fn archive_season(
+    tx: &mut Transaction<'_, Postgres>,
+    season_id: i32,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO season_standings (season_id, game_type, user_id, rank, rating, games_played, badge)
+        SELECT $1, game_type, user_id, rank, rating, season_games,
+            CASE
+                WHEN rank = 1 THEN 'Champion'
+                WHEN rank <= 3 THEN 'Podium'
+                WHEN rank <= 10 THEN 'Top 10'
+                WHEN rank <= CEIL(players * 0.1) THEN 'Top 10%'
+            END
+        FROM (
+            SELECT game_type, user_id, rating, season_games,
+                RANK() OVER (PARTITION BY game_type ORDER BY rating DESC)::INT AS rank,
+                COUNT(*) OVER (PARTITION BY game_type) AS players
+            FROM ratings
+            WHERE season_games > 0
+        ) s;",
+        season_id
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    // Pull everyone toward the mean and make their rating less certain, so the new
+    // season's games move them more. Inactivity is left alone so decay still applies.
+    let reset = SoftReset::from_env();
+    sqlx::query!(
+        "UPDATE ratings
+        SET rating = $1 + (rating - $1) * (1 - $2::DOUBLE PRECISION),
+            deviation = deviation + ($3 - deviation) * $2,
+            season_games = 0;",
+        reset.mean,
+        reset.factor,
+        rating_service::DEFAULT_DEVIATION,
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    sqlx::query!(
+        "UPDATE seasons SET status = 'archived', archived_at = NOW() WHERE id = $1;",
+        season_id
+    )
+    .execute(&mut **tx)
+    .await?;
+    return Ok(());
+}
+
+pub async fn list_seasons(pool: Pool<Postgres>) -> Result<Vec<SeasonSummary>, sqlx::Error> {
+    let seasons = sqlx::query!(
+        "SELECT id, name, starts_at, ends_at, status FROM seasons ORDER BY starts_at DESC, id DESC;"
+    )
+    .fetch_all(&pool)
+    .await?
+    .into_iter()
+    .map(|s| SeasonSummary {
+        id: s.id,
+        name: s.name,
+        starts_at: format_date(s.starts_at),
+        ends_at: format_date(s.ends_at),
+        status: s.status,
+    })
+    .collect();
+    return Ok(seasons);
+}
+
+// Final standings of a season for one game type, or None if there's no such season
+pub async fn get_season_standings(
+    pool: Pool<Postgres>,
+    season_id: i32,
+    game_type: GameType,
+) -> Result<Option<SeasonStandingsView>, sqlx::Error> {
+    let season = sqlx::query!(
+        "SELECT id, name, starts_at, ends_at, status FROM seasons WHERE id = $1;",
+        season_id
+    )
+    .fetch_optional(&pool)
+    .await?;
+    let Some(season) = season else {
+        return Ok(None);
+    };
+
+    let standings = sqlx::query!(
+        "SELECT s.rank, u.username, s.rating, s.games_played, s.badge
+        FROM season_standings s
+        JOIN users u ON u.id = s.user_id
+        WHERE s.season_id = $1 AND s.game_type = $2
+        ORDER BY s.rank, u.username;",
+        season_id,
+        game_type.as_str()
+    )
+    .fetch_all(&pool)
+    .await?
+    .into_iter()
+    .map(|s| SeasonStanding {
+        rank: s.rank,
+        username: s.username,
+        rating: s.rating.round() as i64,
+        games_played: s.games_played,
+        badge: s.badge,
+    })
+    .collect();
+
+    return Ok(Some(SeasonStandingsView {
+        season: SeasonSummary {
+            id: season.id,
+            name: season.name,
+            starts_at: format_date(season.starts_at),
+            ends_at: format_date(season.ends_at),
+            status: season.status,
+        },
+        game_type: game_type.as_str().to_string(),
+        standings,
+    }));
+}
+
+// Badges the player earned in past seasons, newest first
+pub async fn get_badges<'e, E: PgExecutor<'e>>(
+    executor: E,
+    user_id: Uuid,
+) -> Result<Vec<SeasonBadge>, sqlx::Error> {
+    let badges = sqlx::query!(
+        r#"SELECT s.id AS season_id, s.name AS season, st.game_type, st.rank, st.badge AS "badge!"
+        FROM season_standings st
+        JOIN seasons s ON s.id = st.season_id
+        WHERE st.user_id = $1 AND st.badge IS NOT NULL
+        ORDER BY s.starts_at DESC, st.game_type;"#,
+        user_id
+    )
+    .fetch_all(executor)
+    .await?
+    .into_iter()
+    .map(|b| SeasonBadge {
+        season_id: b.season_id,
+        season: b.season,
+        game_type: b.game_type,
+        rank: b.rank,
+        badge: b.badge,
+    })
+    .collect();
+    return Ok(badges);
+}
+
+fn format_date(time: DateTime<Utc>) -> String {
+    return time.format("%Y-%m-%d").to_string();
+}
@@ -0,0 +1,419 @@
+use std::{
+    collections::HashMap,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use sqlx::{Pool, Postgres};
+use uuid::Uuid;
+
+use super::token_service::{self, hash_token};
+
+const DEFAULT_SESSION_DAYS: i64 = 30;
+const DEFAULT_SESSION_CACHE_SECONDS: u64 = 30;
+// How long after a refresh token is rotated it's still accepted without counting as reuse,
+// so requests the browser sent in parallel with the same cookie don't end the session
+const DEFAULT_REFRESH_REUSE_GRACE_SECONDS: f64 = 10.0;
+
+// Remembers sessions that were recently found valid so the auth middleware doesn't
+// hit the database on every request. Revoking a session evicts it here straight away,
+// other server instances stop accepting it once their entry is older than the TTL.
+pub struct SessionCache {
+    // Keyed by session id, with the session's user and when it was last checked
+    entries: RwLock<HashMap<Uuid, (Uuid, Instant)>>,
+    ttl: Duration,
+}
+
+impl SessionCache {
+    pub fn from_env() -> Self {
+        let seconds = dotenv::var("SESSION_CACHE_SECONDS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_SESSION_CACHE_SECONDS);
+        return SessionCache {
+            entries: RwLock::new(HashMap::new()),
+            ttl: Duration::from_secs(seconds),
+        };
+    }
+
+    fn is_fresh(&self, session_id: Uuid) -> bool {
+        let entries = self.entries.read().unwrap();
+        return entries
+            .get(&session_id)
+            .is_some_and(|(_, checked_at)| checked_at.elapsed() < self.ttl);
+    }
+
+    fn insert(&self, session_id: Uuid, user_id: Uuid) {
+        let mut entries = self.entries.write().unwrap();
+        // Drop stale entries now and then so the map doesn't grow forever
+        if entries.len() > 10_000 {
+            entries.retain(|_, (_, checked_at)| checked_at.elapsed() < self.ttl);
+        }
+        entries.insert(session_id, (user_id, Instant::now()));
+    }
+
+    fn evict(&self, session_id: Uuid) {
+        self.entries.write().unwrap().remove(&session_id);
+    }
+
+    fn evict_user(&self, user_id: Uuid) {
+        self.entries
+            .write()
+            .unwrap()
+            .retain(|_, (cached_user_id, _)| *cached_user_id != user_id);
+    }
+}
+
+// A session and the refresh token it can currently be continued with, which only the client keeps
+pub struct NewSession {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub refresh_token: String,
+}
+
+pub enum Refresh {
+    // The refresh token was swapped for a new one
+    Rotated(NewSession),
+    // The refresh token was rotated moments ago by a request sent alongside this one.
+    // The session carries on, but the new tokens went to that other request, so this one gets none.
+    Raced,
+    // Unknown, expired, or replayed after rotation, in which case the session is revoked
+    Rejected,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct SessionView {
+    pub id: Uuid,
+    pub user_agent: Option<String>,
+    pub created_at: String,
+    pub last_used_at: String,
+    // Whether this is the session the list was asked for with
+    pub current: bool,
+}
+
+fn session_days() -> i64 {
+    return dotenv::var("SESSION_DAYS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_SESSION_DAYS);
+}
+
+// How long a session lasts, for the refresh cookie's Max-Age
+pub fn session_seconds() -> i64 {
+    return chrono::Duration::days(session_days()).num_seconds();
+}
+
+// Starts a session lasting SESSION_DAYS and clears out the user's expired ones
+pub async fn create_session(
+    pool: &Pool<Postgres>,
+    user_id: Uuid,
+    user_agent: Option<&str>,
+) -> Result<NewSession, sqlx::Error> {
+    let refresh_token = token_service::new_token();
+    let expires_at = Utc::now() + chrono::Duration::days(session_days());
+
+    sqlx::query!(
+        "DELETE FROM user_sessions WHERE user_id = $1 AND expires_at <= NOW();",
+        user_id
+    )
+    .execute(pool)
+    .await?;
+    let id = sqlx::query_scalar!(
+        "INSERT INTO user_sessions (user_id, token, expires_at, user_agent)
+        VALUES ($1, $2, $3, $4)
+        RETURNING id;",
+        user_id,
+        hash_token(&refresh_token),
+        expires_at,
+        user_agent
+    )
+    .fetch_one(pool)
+    .await?;
+    return Ok(NewSession {
+        id,
+        user_id,
+        refresh_token,
+    });
+}
+
+// Swaps a refresh token for a new one. A token that was already rotated revokes its
+// whole session, since it means someone else has a copy, unless it was rotated within
+// REFRESH_REUSE_GRACE_SECONDS.
+pub async fn refresh_session(
+    pool: &Pool<Postgres>,
+    cache: &SessionCache,
+    refresh_token: &str,
+) -> Result<Refresh, sqlx::Error> {
+    let token_hash = hash_token(refresh_token);
+    let mut tx = pool.begin().await?;
+
+    let session = sqlx::query!(
+        r#"SELECT id, user_id AS "user_id!" FROM user_sessions
+        WHERE token = $1 AND expires_at > NOW()
+        FOR UPDATE;"#,
+        token_hash
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    if let Some(session) = session {
+        let new_token = token_service::new_token();
+        sqlx::query!(
+            "UPDATE user_sessions SET token = $2, last_used_at = NOW() WHERE id = $1;",
+            session.id,
+            hash_token(&new_token)
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            "INSERT INTO refresh_tokens (token_hash, session_id) VALUES ($1, $2);",
+            token_hash,
+            session.id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        return Ok(Refresh::Rotated(NewSession {
+            id: session.id,
+            user_id: session.user_id,
+            refresh_token: new_token,
+        }));
+    }
+
+    let grace = dotenv::var("REFRESH_REUSE_GRACE_SECONDS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_REFRESH_REUSE_GRACE_SECONDS);
+    let rotated = sqlx::query!(
+        r#"SELECT r.session_id,
+            r.rotated_at > NOW() - make_interval(secs => $2) AS "in_grace!"
+        FROM refresh_tokens r
+        JOIN user_sessions s ON s.id = r.session_id
+        WHERE r.token_hash = $1 AND s.expires_at > NOW();"#,
+        token_hash,
+        grace
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    match rotated {
+        Some(rotated) if rotated.in_grace => return Ok(Refresh::Raced),
+        Some(rotated) => {
+            tracing::warn!(
+                "refresh token reused, revoking session {}",
+                rotated.session_id
+            );
+            sqlx::query!(
+                "DELETE FROM user_sessions WHERE id = $1;",
+                rotated.session_id
+            )
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+            cache.evict(rotated.session_id);
+            return Ok(Refresh::Rejected);
+        }
+        None => return Ok(Refresh::Rejected),
+    }
+}
+
+// Whether the session is still active. Answers from the cache when it can.
+pub async fn check_session(
+    pool: &Pool<Postgres>,
+    cache: &SessionCache,
+    session_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    if cache.is_fresh(session_id) {
+        return Ok(true);
+    }
+
+    let user_id = sqlx::query_scalar!(
+        r#"UPDATE user_sessions SET last_used_at = NOW()
+        WHERE id = $1 AND expires_at > NOW()
+        RETURNING user_id AS "user_id!";"#,
+        session_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    match user_id {
+        Some(user_id) => {
+            cache.insert(session_id, user_id);
+            return Ok(true);
+        }
+        None => {
+            cache.evict(session_id);
+            return Ok(false);
+        }
+    }
+}
+
+// Ends one of the user's sessions. Returns whether there was such a session.
+pub async fn revoke_session(
+    pool: &Pool<Postgres>,
+    cache: &SessionCache,
+    user_id: Uuid,
+    session_id: Uuid,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "DELETE FROM user_sessions WHERE id = $1 AND user_id = $2;",
+        session_id,
+        user_id
+    )
+    .execute(pool)
+    .await?;
+    cache.evict(session_id);
+    return Ok(result.rows_affected() > 0);
+}
+
+// Ends every session the user has, logging them out everywhere
+pub async fn revoke_all_sessions(
+    pool: &Pool<Postgres>,
+    cache: &SessionCache,
+    user_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM user_sessions WHERE user_id = $1;", user_id)
+        .execute(pool)
+        .await?;
+    cache.evict_user(user_id);
+    return Ok(());
+}
+
+// The user's active sessions, most recently used first
+pub async fn list_sessions(
+    pool: &Pool<Postgres>,
+    user_id: Uuid,
+    current_session_id: Uuid,
+) -> Result<Vec<SessionView>, sqlx::Error> {
+    let sessions = sqlx::query!(
+        "SELECT id, user_agent, created_at, last_used_at FROM user_sessions
+        WHERE user_id = $1 AND expires_at > NOW()
+        ORDER BY last_used_at DESC;",
+        user_id
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|s| SessionView {
+        id: s.id,
+        user_agent: s.user_agent,
+        created_at: s
+            .created_at
+            .map(|at| at.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_default(),
+        last_used_at: s.last_used_at.format("%Y-%m-%d %H:%M").to_string(),
+        current: s.id == current_session_id,
+    })
+    .collect();
+    return Ok(sessions);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Each test gets its own migrated database from DATABASE_URL
+    async fn start_session(pool: &Pool<Postgres>) -> NewSession {
+        let user_id = sqlx::query_scalar!(
+            "INSERT INTO users (username, email) VALUES ('alice', 'alice@example.com') RETURNING id;"
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap();
+        return create_session(pool, user_id, None).await.unwrap();
+    }
+
+    async fn session_exists(pool: &Pool<Postgres>, session_id: Uuid) -> bool {
+        return sqlx::query_scalar!(
+            r#"SELECT EXISTS (SELECT 1 FROM user_sessions WHERE id = $1) AS "exists!";"#,
+            session_id
+        )
+        .fetch_one(pool)
+        .await
+        .unwrap();
+    }
+
+    #[sqlx::test]
+    async fn refresh_rotates_the_token(pool: Pool<Postgres>) {
+        let cache = SessionCache::from_env();
+        let session = start_session(&pool).await;
+
+        let Refresh::Rotated(rotated) = refresh_session(&pool, &cache, &session.refresh_token)
+            .await
+            .unwrap()
+        else {
+            panic!("expected the refresh token to be rotated");
+        };
+        assert_eq!(rotated.id, session.id);
+        assert_eq!(rotated.user_id, session.user_id);
+        assert_ne!(rotated.refresh_token, session.refresh_token);
+
+        // The new token carries on the same session
+        let Refresh::Rotated(again) = refresh_session(&pool, &cache, &rotated.refresh_token)
+            .await
+            .unwrap()
+        else {
+            panic!("expected the new refresh token to be rotated");
+        };
+        assert_eq!(again.id, session.id);
+    }
+
+    #[sqlx::test]
+    async fn reuse_within_grace_keeps_the_session_without_new_tokens(pool: Pool<Postgres>) {
+        let cache = SessionCache::from_env();
+        let session = start_session(&pool).await;
+        let Refresh::Rotated(rotated) = refresh_session(&pool, &cache, &session.refresh_token)
+            .await
+            .unwrap()
+        else {
+            panic!("expected the refresh token to be rotated");
+        };
+
+        let reused = refresh_session(&pool, &cache, &session.refresh_token)
+            .await
+            .unwrap();
+        assert!(matches!(reused, Refresh::Raced));
+        assert!(session_exists(&pool, session.id).await);
+        // The token the winning request got still works
+        let next = refresh_session(&pool, &cache, &rotated.refresh_token)
+            .await
+            .unwrap();
+        assert!(matches!(next, Refresh::Rotated(_)));
+    }
+
+    #[sqlx::test]
+    async fn reuse_after_grace_revokes_the_session(pool: Pool<Postgres>) {
+        let cache = SessionCache::from_env();
+        let session = start_session(&pool).await;
+        let Refresh::Rotated(rotated) = refresh_session(&pool, &cache, &session.refresh_token)
+            .await
+            .unwrap()
+        else {
+            panic!("expected the refresh token to be rotated");
+        };
+        sqlx::query!("UPDATE refresh_tokens SET rotated_at = NOW() - INTERVAL '1 hour';")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let reused = refresh_session(&pool, &cache, &session.refresh_token)
+            .await
+            .unwrap();
+        assert!(matches!(reused, Refresh::Rejected));
+        assert!(!session_exists(&pool, session.id).await);
+        // Whoever holds the current token is signed out too
+        let next = refresh_session(&pool, &cache, &rotated.refresh_token)
+            .await
+            .unwrap();
+        assert!(matches!(next, Refresh::Rejected));
+    }
+
+    #[sqlx::test]
+    async fn unknown_token_is_rejected(pool: Pool<Postgres>) {
+        let cache = SessionCache::from_env();
+        start_session(&pool).await;
+
+        let refresh = refresh_session(&pool, &cache, &token_service::new_token())
+            .await
+            .unwrap();
+        assert!(matches!(refresh, Refresh::Rejected));
+    }
+}
@@ -0,0 +1,16 @@
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+// Random bytes in an emailed or cookie token, sent hex encoded
+const TOKEN_BYTES: usize = 32;
+
+// A new random token to hand to the user. Only its hash_token should be stored.
+pub fn new_token() -> String {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    return hex::encode(bytes);
+}
+
+pub fn hash_token(token: &str) -> String {
+    return hex::encode(Sha256::digest(token));
+}
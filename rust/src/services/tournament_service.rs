@@ -0,0 +1,1399 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    str::FromStr,
+    time::Duration,
+};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use sqlx::{PgConnection, Pool, Postgres, Transaction};
+use uuid::Uuid;
+
+use super::{
+    events_service::{self, GameEvent},
+    game_service,
+    matchmaking_service::GameType,
+    rating_service,
+};
+
+// How often the scheduler looks for tournaments that are due to start
+const TOURNAMENT_SCHEDULER_INTERVAL: Duration = Duration::from_secs(5);
+const MIN_TOURNAMENT_SIZE: i32 = 2;
+const MAX_TOURNAMENT_SIZE: i32 = 64;
+const MAX_TOURNAMENT_NAME_LENGTH: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TournamentFormat {
+    SingleElimination,
+    Swiss,
+    RoundRobin,
+}
+
+impl TournamentFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TournamentFormat::SingleElimination => "single_elimination",
+            TournamentFormat::Swiss => "swiss",
+            TournamentFormat::RoundRobin => "round_robin",
+        }
+    }
+
+    pub fn display_name(&self) -> &'static str {
+        match self {
+            TournamentFormat::SingleElimination => "Single elimination",
+            TournamentFormat::Swiss => "Swiss",
+            TournamentFormat::RoundRobin => "Round robin",
+        }
+    }
+
+    // Number of rounds needed for this many players
+    pub fn rounds(&self, players: usize) -> i32 {
+        let to_decide_winner = players.next_power_of_two().trailing_zeros() as i32;
+        match self {
+            TournamentFormat::SingleElimination => to_decide_winner,
+            // Enough rounds for a single undefeated player, without running out of new opponents
+            TournamentFormat::Swiss => to_decide_winner.min(players as i32 - 1),
+            // Everyone plays everyone, sitting out one round each when there's an odd number
+            TournamentFormat::RoundRobin => (players + players % 2) as i32 - 1,
+        }
+    }
+}
+
+impl FromStr for TournamentFormat {
+    type Err = TournamentError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "single_elimination" => Ok(TournamentFormat::SingleElimination),
+            "swiss" => Ok(TournamentFormat::Swiss),
+            "round_robin" => Ok(TournamentFormat::RoundRobin),
+            _ => Err(TournamentError::InvalidFormat(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TournamentError {
+    InvalidName,
+    InvalidSize(i32),
+    InvalidStartTime,
+    InvalidFormat(String),
+    NotFound,
+    RegistrationClosed,
+    Full,
+    AlreadyRegistered,
+    Database(sqlx::Error),
+}
+
+impl fmt::Display for TournamentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TournamentError::InvalidName => write!(
+                f,
+                "Tournament names must be between 1 and {} characters",
+                MAX_TOURNAMENT_NAME_LENGTH
+            ),
+            TournamentError::InvalidSize(size) => write!(
+                f,
+                "Tournaments must have between {} and {} players, not {}",
+                MIN_TOURNAMENT_SIZE, MAX_TOURNAMENT_SIZE, size
+            ),
+            TournamentError::InvalidStartTime => {
+                write!(f, "The start time must be a valid time in the future")
+            }
+            TournamentError::InvalidFormat(format) => {
+                write!(f, "{} is not a tournament format", format)
+            }
+            TournamentError::NotFound => write!(f, "Tournament not found"),
+            TournamentError::RegistrationClosed => {
+                write!(f, "Registration for this tournament is closed")
+            }
+            TournamentError::Full => write!(f, "This tournament is full"),
+            TournamentError::AlreadyRegistered => {
+                write!(f, "You're already registered for this tournament")
+            }
+            TournamentError::Database(e) => write!(f, "Database error: {}", e),
+        }
+    }
+}
+
+impl From<sqlx::Error> for TournamentError {
+    fn from(e: sqlx::Error) -> Self {
+        return TournamentError::Database(e);
+    }
+}
+
+// Parses a start time from a datetime-local input, which is taken to be in UTC
+fn parse_start_time(starts_at: &str) -> Result<DateTime<Utc>, TournamentError> {
+    let starts_at = NaiveDateTime::parse_from_str(starts_at, "%Y-%m-%dT%H:%M")
+        .or_else(|_| NaiveDateTime::parse_from_str(starts_at, "%Y-%m-%dT%H:%M:%S"))
+        .map_err(|_| TournamentError::InvalidStartTime)?
+        .and_utc();
+    if starts_at <= Utc::now() {
+        return Err(TournamentError::InvalidStartTime);
+    }
+    return Ok(starts_at);
+}
+
+pub async fn create_tournament(
+    pool: Pool<Postgres>,
+    created_by: Uuid,
+    name: &str,
+    format: TournamentFormat,
+    size: i32,
+    starts_at: &str,
+) -> Result<Uuid, TournamentError> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_TOURNAMENT_NAME_LENGTH {
+        return Err(TournamentError::InvalidName);
+    }
+    if !(MIN_TOURNAMENT_SIZE..=MAX_TOURNAMENT_SIZE).contains(&size) {
+        return Err(TournamentError::InvalidSize(size));
+    }
+    let starts_at = parse_start_time(starts_at)?;
+
+    let mut tx = pool.begin().await?;
+    let tournament_id = sqlx::query_scalar!(
+        "INSERT INTO tournaments (name, format, size, starts_at, created_by)
+        VALUES ($1, $2, $3, $4, $5)
+        RETURNING id;",
+        name,
+        format.as_str(),
+        size,
+        starts_at,
+        created_by,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    events_service::publish(&mut *tx, &GameEvent::TournamentUpdate { tournament_id }).await?;
+    tx.commit().await?;
+
+    tracing::debug!("Created tournament {} ({})", tournament_id, name);
+    return Ok(tournament_id);
+}
+
+pub async fn register_player(
+    pool: Pool<Postgres>,
+    tournament_id: Uuid,
+    player_id: Uuid,
+) -> Result<(), TournamentError> {
+    let mut tx = pool.begin().await?;
+
+    // Lock the tournament so two late registrations can't both take the last spot
+    let tournament = sqlx::query!(
+        r#"SELECT status, size,
+            (SELECT COUNT(*) FROM tournament_players WHERE tournament_id = $1) AS "registered!"
+        FROM tournaments
+        WHERE id = $1
+        FOR UPDATE;"#,
+        tournament_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(TournamentError::NotFound)?;
+
+    if tournament.status != "registering" {
+        return Err(TournamentError::RegistrationClosed);
+    }
+    if tournament.registered >= tournament.size as i64 {
+        return Err(TournamentError::Full);
+    }
+
+    let res = sqlx::query!(
+        "INSERT INTO tournament_players (tournament_id, user_id) VALUES ($1, $2)
+        ON CONFLICT (tournament_id, user_id) DO NOTHING;",
+        tournament_id,
+        player_id,
+    )
+    .execute(&mut *tx)
+    .await?;
+    if res.rows_affected() == 0 {
+        return Err(TournamentError::AlreadyRegistered);
+    }
+
+    events_service::publish(&mut *tx, &GameEvent::TournamentUpdate { tournament_id }).await?;
+    tx.commit().await?;
+    return Ok(());
+}
+
+// Runs forever, starting tournaments once their start time comes around.
+// Tournaments are locked with SKIP LOCKED so several server instances can run this at once.
+pub async fn run_tournament_scheduler(pool: Pool<Postgres>) {
+    let mut interval = tokio::time::interval(TOURNAMENT_SCHEDULER_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = start_due_tournaments(&pool).await {
+            tracing::error!("error starting tournaments {}", e.to_string());
+        }
+    }
+}
+
+// Starts each due tournament in its own transaction, so one that fails to start
+// doesn't hold back the others
+async fn start_due_tournaments(pool: &Pool<Postgres>) -> Result<(), sqlx::Error> {
+    let due = sqlx::query_scalar!(
+        "SELECT id FROM tournaments
+        WHERE status = 'registering' AND starts_at <= NOW()
+        ORDER BY starts_at;"
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for tournament_id in due {
+        let mut tx = pool.begin().await?;
+        // Another instance may have picked it up since
+        let still_due = sqlx::query_scalar!(
+            "SELECT id FROM tournaments
+            WHERE id = $1 AND status = 'registering'
+            FOR UPDATE SKIP LOCKED;",
+            tournament_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if still_due.is_none() {
+            continue;
+        }
+
+        match start_tournament(&mut tx, tournament_id).await {
+            Ok(()) => tx.commit().await?,
+            Err(e) => tracing::error!(
+                "error starting tournament {} {}",
+                tournament_id,
+                e.to_string()
+            ),
+        }
+    }
+    return Ok(());
+}
+
+// Seeds the registered players by ranked rating and sets up the first round.
+// Tournaments without enough players to play a match are cancelled.
+async fn start_tournament(
+    tx: &mut Transaction<'_, Postgres>,
+    tournament_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    let format = sqlx::query_scalar!(
+        "SELECT format FROM tournaments WHERE id = $1;",
+        tournament_id
+    )
+    .fetch_one(&mut **tx)
+    .await?;
+    let format = format
+        .parse::<TournamentFormat>()
+        .unwrap_or(TournamentFormat::SingleElimination);
+
+    let players = sqlx::query!(
+        r#"SELECT tp.user_id, COALESCE(r.rating, $2) AS "rating!"
+        FROM tournament_players tp
+        LEFT JOIN ratings r ON r.user_id = tp.user_id AND r.game_type = $3
+        WHERE tp.tournament_id = $1
+        ORDER BY 2 DESC, tp.registered_at;"#,
+        tournament_id,
+        rating_service::DEFAULT_RATING,
+        GameType::Ranked.as_str(),
+    )
+    .fetch_all(&mut **tx)
+    .await?;
+
+    if players.len() < MIN_TOURNAMENT_SIZE as usize {
+        sqlx::query!(
+            "UPDATE tournaments SET status = 'cancelled' WHERE id = $1;",
+            tournament_id
+        )
+        .execute(&mut **tx)
+        .await?;
+        events_service::publish(&mut **tx, &GameEvent::TournamentUpdate { tournament_id }).await?;
+        tracing::debug!("Cancelled tournament {}, not enough players", tournament_id);
+        return Ok(());
+    }
+
+    for (i, player) in players.iter().enumerate() {
+        sqlx::query!(
+            "UPDATE tournament_players SET seed = $3, rating = $4
+            WHERE tournament_id = $1 AND user_id = $2;",
+            tournament_id,
+            player.user_id,
+            i as i32 + 1,
+            player.rating,
+        )
+        .execute(&mut **tx)
+        .await?;
+    }
+
+    sqlx::query!(
+        "UPDATE tournaments SET status = 'in_progress', rounds = $2 WHERE id = $1;",
+        tournament_id,
+        format.rounds(players.len()),
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    let seeded: Vec<Uuid> = players.iter().map(|p| p.user_id).collect();
+    match format {
+        TournamentFormat::SingleElimination => create_bracket(tx, tournament_id, &seeded).await?,
+        TournamentFormat::Swiss => create_swiss_round(tx, tournament_id, 1).await?,
+        TournamentFormat::RoundRobin => {
+            create_round_robin(tx, tournament_id, &seeded).await?;
+            start_round(tx, tournament_id, 1).await?;
+        }
+    }
+
+    events_service::publish(&mut **tx, &GameEvent::TournamentUpdate { tournament_id }).await?;
+    tracing::debug!(
+        "Started {} tournament {} with {} players",
+        format.as_str(),
+        tournament_id,
+        seeded.len()
+    );
+    return Ok(());
+}
+
+// Adds a match to a round. A match with only one player is a bye, won by that
+// player if `award_bye` is set.
+async fn insert_match(
+    tx: &mut Transaction<'_, Postgres>,
+    tournament_id: Uuid,
+    round: i32,
+    position: i32,
+    player1: Option<Uuid>,
+    player2: Option<Uuid>,
+    award_bye: bool,
+) -> Result<i32, sqlx::Error> {
+    let bye_player = match (player1, player2) {
+        (Some(_), Some(_)) => None,
+        (player, None) | (None, player) => player,
+    };
+    let is_bye = bye_player.is_some();
+    return sqlx::query_scalar!(
+        "INSERT INTO tournament_matches
+            (tournament_id, round, position, player1_id, player2_id, winner_id, status)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        RETURNING id;",
+        tournament_id,
+        round,
+        position,
+        player1,
+        player2,
+        bye_player.filter(|_| award_bye),
+        if is_bye { "bye" } else { "waiting" },
+    )
+    .fetch_one(&mut **tx)
+    .await;
+}
+
+// Bracket positions of each seed, so the top seeds can only meet in the last rounds.
+// For 8 players this is 1, 8, 4, 5, 2, 7, 3, 6.
+fn seed_order(size: usize) -> Vec<usize> {
+    let mut order = vec![1];
+    while order.len() < size {
+        let total = order.len() * 2 + 1;
+        order = order
+            .iter()
+            .flat_map(|&seed| [seed, total - seed])
+            .collect();
+    }
+    return order;
+}
+
+// First round pairings of a bracket for players in seed order. Missing seeds are byes.
+fn bracket_pairings(seeded: &[Uuid]) -> Vec<(Option<Uuid>, Option<Uuid>)> {
+    return seed_order(seeded.len().next_power_of_two())
+        .chunks_exact(2)
+        .map(|seeds| {
+            (
+                seeded.get(seeds[0] - 1).copied(),
+                seeded.get(seeds[1] - 1).copied(),
+            )
+        })
+        .collect();
+}
+
+// Creates every match in a single-elimination bracket for players in seed order.
+// Fields that aren't a power of two are padded with byes, which go to the top seeds.
+async fn create_bracket(
+    tx: &mut Transaction<'_, Postgres>,
+    tournament_id: Uuid,
+    seeded: &[Uuid],
+) -> Result<(), sqlx::Error> {
+    let size = seeded.len().next_power_of_two();
+    let rounds = size.trailing_zeros() as i32;
+
+    // Later rounds first, so first round winners have somewhere to go
+    sqlx::query!(
+        "INSERT INTO tournament_matches (tournament_id, round, position)
+        SELECT $1, round, position
+        FROM generate_series(2, $2) AS round, generate_series(0, ($3 >> round) - 1) AS position;",
+        tournament_id,
+        rounds,
+        size as i32,
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    for (position, (player1, player2)) in bracket_pairings(seeded).into_iter().enumerate() {
+        let tournament_match_id = insert_match(
+            tx,
+            tournament_id,
+            1,
+            position as i32,
+            player1,
+            player2,
+            true,
+        )
+        .await?;
+
+        match (player1, player2) {
+            (Some(player1), Some(player2)) => {
+                start_match(tx, tournament_match_id, player1, player2).await?;
+            }
+            (Some(winner), None) | (None, Some(winner)) => {
+                advance_winner(tx, tournament_id, 1, position as i32, winner).await?;
+            }
+            (None, None) => {}
+        }
+    }
+
+    return Ok(());
+}
+
+// Every round of a round robin using the circle method: one player stays put while
+// the rest rotate around them. With an odd number of players someone sits out each round.
+fn round_robin_rounds(players: &[Uuid]) -> Vec<Vec<(Option<Uuid>, Option<Uuid>)>> {
+    let mut slots: Vec<Option<Uuid>> = players.iter().copied().map(Some).collect();
+    if slots.len() % 2 == 1 {
+        slots.push(None);
+    }
+    let n = slots.len();
+
+    let mut rounds = Vec::new();
+    for _ in 1..n {
+        rounds.push((0..n / 2).map(|i| (slots[i], slots[n - 1 - i])).collect());
+        slots[1..].rotate_right(1);
+    }
+    return rounds;
+}
+
+// Creates every match of a round robin up front. Rounds are started one at a time.
+// Sitting out a round isn't worth any points, everyone sits out the same number of rounds.
+async fn create_round_robin(
+    tx: &mut Transaction<'_, Postgres>,
+    tournament_id: Uuid,
+    seeded: &[Uuid],
+) -> Result<(), sqlx::Error> {
+    for (round, pairings) in round_robin_rounds(seeded).into_iter().enumerate() {
+        for (position, (player1, player2)) in pairings.into_iter().enumerate() {
+            insert_match(
+                tx,
+                tournament_id,
+                round as i32 + 1,
+                position as i32,
+                player1,
+                player2,
+                false,
+            )
+            .await?;
+        }
+    }
+    return Ok(());
+}
+
+// Starts every match in a round that's waiting on its players
+async fn start_round(
+    tx: &mut Transaction<'_, Postgres>,
+    tournament_id: Uuid,
+    round: i32,
+) -> Result<(), sqlx::Error> {
+    let waiting = sqlx::query!(
+        r#"SELECT id, player1_id AS "player1_id!", player2_id AS "player2_id!"
+        FROM tournament_matches
+        WHERE tournament_id = $1 AND round = $2 AND status = 'waiting'
+            AND player1_id IS NOT NULL AND player2_id IS NOT NULL
+        ORDER BY position;"#,
+        tournament_id,
+        round,
+    )
+    .fetch_all(&mut **tx)
+    .await?;
+
+    for m in waiting {
+        start_match(tx, m.id, m.player1_id, m.player2_id).await?;
+    }
+    return Ok(());
+}
+
+// Swiss pairings for the next round. Players are paired down the standings with the
+// closest ranked player they haven't played yet. With an odd number of players the
+// lowest ranked player who hasn't had a bye yet gets one.
+// Returns the pairs and the player getting a bye.
+fn swiss_pairings(
+    ranked: &[Uuid],
+    played: &HashSet<(Uuid, Uuid)>,
+    had_bye: &HashSet<Uuid>,
+) -> (Vec<(Uuid, Uuid)>, Option<Uuid>) {
+    let mut remaining = ranked.to_vec();
+    let mut bye = None;
+    if remaining.len() % 2 == 1 {
+        let i = remaining
+            .iter()
+            .rposition(|p| !had_bye.contains(p))
+            .unwrap_or(remaining.len() - 1);
+        bye = Some(remaining.remove(i));
+    }
+
+    let have_played = |a: Uuid, b: Uuid| played.contains(&(a, b)) || played.contains(&(b, a));
+    // If there's no way around a rematch, pair straight down the standings
+    let pairs = pair_without_rematches(&remaining, &have_played).unwrap_or_else(|| {
+        remaining
+            .chunks_exact(2)
+            .map(|pair| (pair[0], pair[1]))
+            .collect()
+    });
+    return (pairs, bye);
+}
+
+fn pair_without_rematches(
+    players: &[Uuid],
+    have_played: &dyn Fn(Uuid, Uuid) -> bool,
+) -> Option<Vec<(Uuid, Uuid)>> {
+    let Some((&first, rest)) = players.split_first() else {
+        return Some(Vec::new());
+    };
+    for (i, &opponent) in rest.iter().enumerate() {
+        if have_played(first, opponent) {
+            continue;
+        }
+        let mut others = rest.to_vec();
+        others.remove(i);
+        if let Some(mut pairs) = pair_without_rematches(&others, have_played) {
+            pairs.insert(0, (first, opponent));
+            return Some(pairs);
+        }
+    }
+    return None;
+}
+
+// Pairs and starts the next round of a Swiss tournament from the current standings
+async fn create_swiss_round(
+    tx: &mut Transaction<'_, Postgres>,
+    tournament_id: Uuid,
+    round: i32,
+) -> Result<(), sqlx::Error> {
+    let standings = load_standings(tx, tournament_id).await?;
+    let ranked: Vec<Uuid> = standings.iter().map(|s| s.player_id).collect();
+
+    let previous = sqlx::query!(
+        "SELECT player1_id, player2_id FROM tournament_matches WHERE tournament_id = $1;",
+        tournament_id
+    )
+    .fetch_all(&mut **tx)
+    .await?;
+    let mut played = HashSet::new();
+    let mut had_bye = HashSet::new();
+    for m in previous {
+        match (m.player1_id, m.player2_id) {
+            (Some(player1), Some(player2)) => _ = played.insert((player1, player2)),
+            (Some(player), None) | (None, Some(player)) => _ = had_bye.insert(player),
+            (None, None) => {}
+        }
+    }
+
+    let (pairs, bye) = swiss_pairings(&ranked, &played, &had_bye);
+    for (position, (player1, player2)) in pairs.iter().enumerate() {
+        insert_match(
+            tx,
+            tournament_id,
+            round,
+            position as i32,
+            Some(*player1),
+            Some(*player2),
+            true,
+        )
+        .await?;
+    }
+    if let Some(player) = bye {
+        insert_match(
+            tx,
+            tournament_id,
+            round,
+            pairs.len() as i32,
+            Some(player),
+            None,
+            true,
+        )
+        .await?;
+    }
+
+    return start_round(tx, tournament_id, round).await;
+}
+
+// Starts the game for a tournament match. Both players are entered into the tournament,
+// so the match skips the ready check and goes straight to the game.
+async fn start_match(
+    tx: &mut Transaction<'_, Postgres>,
+    tournament_match_id: i32,
+    player1: Uuid,
+    player2: Uuid,
+) -> Result<(), sqlx::Error> {
+    let match_id = sqlx::query_scalar!(
+        "INSERT INTO matchmaking_matches
+            (player1_id, player2_id, game_type, status, player1_ready, player2_ready)
+        VALUES ($1, $2, $3, 'ready', TRUE, TRUE)
+        RETURNING match_id;",
+        player1,
+        player2,
+        GameType::Tournament.as_str(),
+    )
+    .fetch_one(&mut **tx)
+    .await?;
+    let game_id = game_service::create_game(tx, match_id).await?;
+
+    sqlx::query!(
+        "UPDATE tournament_matches SET match_id = $2, status = 'in_progress' WHERE id = $1;",
+        tournament_match_id,
+        match_id,
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    events_service::publish(
+        &mut **tx,
+        &GameEvent::GameStarted {
+            game_id,
+            match_id,
+            player1_id: player1,
+            player2_id: player2,
+        },
+    )
+    .await?;
+    return Ok(());
+}
+
+async fn finish_tournament(
+    tx: &mut Transaction<'_, Postgres>,
+    tournament_id: Uuid,
+    winner_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE tournaments SET status = 'finished', winner_id = $2, finished_at = NOW()
+        WHERE id = $1;",
+        tournament_id,
+        winner_id,
+    )
+    .execute(&mut **tx)
+    .await?;
+    tracing::debug!("{} won tournament {}", winner_id, tournament_id);
+    return Ok(());
+}
+
+// Moves the winner of a bracket match into the next round, starting that match once
+// both players are in. Winning the last round wins the tournament.
+async fn advance_winner(
+    tx: &mut Transaction<'_, Postgres>,
+    tournament_id: Uuid,
+    round: i32,
+    position: i32,
+    winner_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    let rounds = sqlx::query_scalar!(
+        r#"SELECT rounds AS "rounds!" FROM tournaments WHERE id = $1;"#,
+        tournament_id
+    )
+    .fetch_one(&mut **tx)
+    .await?;
+
+    if round >= rounds {
+        return finish_tournament(tx, tournament_id, winner_id).await;
+    }
+
+    // The row lock taken here means only the second of two feeder matches to finish sees both players
+    let next = sqlx::query!(
+        "UPDATE tournament_matches SET
+            player1_id = CASE WHEN $4 THEN $5 ELSE player1_id END,
+            player2_id = CASE WHEN $4 THEN player2_id ELSE $5 END
+        WHERE tournament_id = $1 AND round = $2 AND position = $3
+        RETURNING id, player1_id, player2_id;",
+        tournament_id,
+        round + 1,
+        position / 2,
+        position % 2 == 0,
+        winner_id,
+    )
+    .fetch_one(&mut **tx)
+    .await?;
+
+    if let (Some(player1), Some(player2)) = (next.player1_id, next.player2_id) {
+        start_match(tx, next.id, player1, player2).await?;
+    }
+    return Ok(());
+}
+
+// Moves a Swiss or round robin tournament on once every match in the round is done.
+// After the last round the top of the standings wins.
+async fn complete_round(
+    tx: &mut Transaction<'_, Postgres>,
+    tournament_id: Uuid,
+    format: TournamentFormat,
+    round: i32,
+    rounds: i32,
+) -> Result<(), sqlx::Error> {
+    let unfinished = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "count!" FROM tournament_matches
+        WHERE tournament_id = $1 AND round = $2 AND status IN ('waiting', 'in_progress');"#,
+        tournament_id,
+        round,
+    )
+    .fetch_one(&mut **tx)
+    .await?;
+    if unfinished > 0 {
+        return Ok(());
+    }
+
+    if round >= rounds {
+        let standings = load_standings(tx, tournament_id).await?;
+        if let Some(leader) = standings.first() {
+            finish_tournament(tx, tournament_id, leader.player_id).await?;
+        }
+        return Ok(());
+    }
+
+    match format {
+        TournamentFormat::Swiss => create_swiss_round(tx, tournament_id, round + 1).await?,
+        _ => start_round(tx, tournament_id, round + 1).await?,
+    }
+    return Ok(());
+}
+
+// Records the result of a finished game if it was played for a tournament,
+// inside the transaction that finishes the game.
+pub async fn record_match_result(
+    tx: &mut Transaction<'_, Postgres>,
+    match_id: i32,
+    winner_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    // Lock the tournament so the last two games of a round finishing at once
+    // can't both miss that the round is over
+    let tournament = sqlx::query!(
+        r#"SELECT t.id, t.format, t.rounds AS "rounds!"
+        FROM tournaments t
+        JOIN tournament_matches tm ON tm.tournament_id = t.id
+        WHERE tm.match_id = $1
+        FOR UPDATE OF t;"#,
+        match_id
+    )
+    .fetch_optional(&mut **tx)
+    .await?;
+    let Some(tournament) = tournament else {
+        return Ok(());
+    };
+
+    let finished = sqlx::query!(
+        "UPDATE tournament_matches SET winner_id = $2, status = 'finished'
+        WHERE match_id = $1
+        RETURNING round, position;",
+        match_id,
+        winner_id,
+    )
+    .fetch_one(&mut **tx)
+    .await?;
+
+    let format = tournament
+        .format
+        .parse::<TournamentFormat>()
+        .unwrap_or(TournamentFormat::SingleElimination);
+    match format {
+        TournamentFormat::SingleElimination => {
+            advance_winner(
+                tx,
+                tournament.id,
+                finished.round,
+                finished.position,
+                winner_id,
+            )
+            .await?
+        }
+        _ => complete_round(tx, tournament.id, format, finished.round, tournament.rounds).await?,
+    }
+    events_service::publish(
+        &mut **tx,
+        &GameEvent::TournamentUpdate {
+            tournament_id: tournament.id,
+        },
+    )
+    .await?;
+    return Ok(());
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Standing {
+    pub rank: usize,
+    pub player_id: Uuid,
+    pub username: String,
+    pub seed: Option<i32>,
+    pub played: i32,
+    pub wins: i32,
+    pub losses: i32,
+    pub byes: i32,
+    pub points: i32,
+    // Sum of the points of every opponent played, the first tiebreak
+    pub buchholz: i32,
+}
+
+struct StandingsPlayer {
+    user_id: Uuid,
+    username: String,
+    seed: Option<i32>,
+}
+
+struct StandingsMatch {
+    player1_id: Option<Uuid>,
+    player2_id: Option<Uuid>,
+    winner_id: Option<Uuid>,
+    status: String,
+}
+
+// Ranks players by points, then Buchholz, then seed. Every win, and every bye
+// that's awarded, is worth a point.
+fn compute_standings(players: Vec<StandingsPlayer>, matches: &[StandingsMatch]) -> Vec<Standing> {
+    let mut standings: HashMap<Uuid, Standing> = players
+        .into_iter()
+        .map(|p| {
+            let standing = Standing {
+                rank: 0,
+                player_id: p.user_id,
+                username: p.username,
+                seed: p.seed,
+                played: 0,
+                wins: 0,
+                losses: 0,
+                byes: 0,
+                points: 0,
+                buchholz: 0,
+            };
+            return (p.user_id, standing);
+        })
+        .collect();
+
+    let mut opponents: Vec<(Uuid, Uuid)> = Vec::new();
+    for m in matches {
+        match (m.player1_id, m.player2_id) {
+            (Some(player1), Some(player2)) if m.status == "finished" => {
+                for (player, opponent) in [(player1, player2), (player2, player1)] {
+                    if let Some(s) = standings.get_mut(&player) {
+                        s.played += 1;
+                        if m.winner_id == Some(player) {
+                            s.wins += 1;
+                            s.points += 1;
+                        } else {
+                            s.losses += 1;
+                        }
+                    }
+                    opponents.push((player, opponent));
+                }
+            }
+            (Some(player), None) | (None, Some(player)) if m.status == "bye" => {
+                if let Some(s) = standings.get_mut(&player) {
+                    s.byes += 1;
+                    if m.winner_id == Some(player) {
+                        s.points += 1;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let points: HashMap<Uuid, i32> = standings.iter().map(|(id, s)| (*id, s.points)).collect();
+    for (player, opponent) in opponents {
+        let opponent_points = points.get(&opponent).copied().unwrap_or(0);
+        if let Some(s) = standings.get_mut(&player) {
+            s.buchholz += opponent_points;
+        }
+    }
+
+    let mut standings: Vec<Standing> = standings.into_values().collect();
+    standings.sort_by(|a, b| {
+        b.points
+            .cmp(&a.points)
+            .then(b.buchholz.cmp(&a.buchholz))
+            .then(a.seed.unwrap_or(i32::MAX).cmp(&b.seed.unwrap_or(i32::MAX)))
+            .then(a.username.cmp(&b.username))
+    });
+    for (i, s) in standings.iter_mut().enumerate() {
+        s.rank = i + 1;
+    }
+    return standings;
+}
+
+async fn load_standings(
+    conn: &mut PgConnection,
+    tournament_id: Uuid,
+) -> Result<Vec<Standing>, sqlx::Error> {
+    let players = sqlx::query_as!(
+        StandingsPlayer,
+        "SELECT tp.user_id, u.username, tp.seed
+        FROM tournament_players tp
+        JOIN users u ON u.id = tp.user_id
+        WHERE tp.tournament_id = $1;",
+        tournament_id
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let matches = sqlx::query_as!(
+        StandingsMatch,
+        "SELECT player1_id, player2_id, winner_id, status
+        FROM tournament_matches
+        WHERE tournament_id = $1;",
+        tournament_id
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    return Ok(compute_standings(players, &matches));
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct StandingsView {
+    pub tournament_id: Uuid,
+    pub name: String,
+    pub format: String,
+    pub status: String,
+    pub standings: Vec<Standing>,
+}
+
+pub async fn get_standings(
+    pool: Pool<Postgres>,
+    tournament_id: Uuid,
+) -> Result<StandingsView, TournamentError> {
+    let tournament = sqlx::query!(
+        "SELECT name, format, status FROM tournaments WHERE id = $1;",
+        tournament_id
+    )
+    .fetch_optional(&pool)
+    .await?
+    .ok_or(TournamentError::NotFound)?;
+
+    return Ok(StandingsView {
+        tournament_id,
+        name: tournament.name,
+        format: tournament.format,
+        status: tournament.status,
+        standings: load_standings(&mut *pool.acquire().await?, tournament_id).await?,
+    });
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct TournamentSummary {
+    pub id: Uuid,
+    pub name: String,
+    pub format: String,
+    pub size: i32,
+    pub registered: i64,
+    pub status: String,
+    pub starts_at: String,
+    pub is_registered: bool,
+    pub can_register: bool,
+}
+
+// Every tournament that hasn't been cancelled, newest first
+pub async fn list_tournaments(
+    pool: Pool<Postgres>,
+    player_id: Uuid,
+) -> Result<Vec<TournamentSummary>, TournamentError> {
+    let rows = sqlx::query!(
+        r#"SELECT t.id, t.name, t.format, t.size, t.status, t.starts_at,
+            COUNT(tp.user_id) AS "registered!",
+            COALESCE(BOOL_OR(tp.user_id = $1), FALSE) AS "is_registered!"
+        FROM tournaments t
+        LEFT JOIN tournament_players tp ON tp.tournament_id = t.id
+        WHERE t.status <> 'cancelled'
+        GROUP BY t.id
+        ORDER BY t.starts_at DESC
+        LIMIT 50;"#,
+        player_id
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    return Ok(rows
+        .into_iter()
+        .map(|t| TournamentSummary {
+            id: t.id,
+            format: format_name(&t.format),
+            can_register: t.status == "registering"
+                && !t.is_registered
+                && t.registered < t.size as i64,
+            name: t.name,
+            size: t.size,
+            registered: t.registered,
+            status: t.status,
+            starts_at: format_time(t.starts_at),
+            is_registered: t.is_registered,
+        })
+        .collect());
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct TournamentPlayerView {
+    pub username: String,
+    pub seed: Option<i32>,
+    pub rating: Option<i64>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct BracketMatchView {
+    pub player1: Option<String>,
+    pub player2: Option<String>,
+    pub winner: Option<String>,
+    pub status: String,
+    pub game_id: Option<Uuid>,
+    // Whether the viewing player can jump into this match's game
+    pub playable: bool,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct BracketRoundView {
+    pub number: i32,
+    pub name: String,
+    pub matches: Vec<BracketMatchView>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct TournamentView {
+    pub id: Uuid,
+    pub name: String,
+    pub format: String,
+    pub size: i32,
+    pub registered: i64,
+    pub status: String,
+    pub starts_at: String,
+    pub winner: Option<String>,
+    pub players: Vec<TournamentPlayerView>,
+    pub rounds: Vec<BracketRoundView>,
+    pub standings: Vec<Standing>,
+    pub is_registered: bool,
+    pub can_register: bool,
+}
+
+pub async fn get_tournament_view(
+    pool: Pool<Postgres>,
+    tournament_id: Uuid,
+    player_id: Uuid,
+) -> Result<TournamentView, TournamentError> {
+    let tournament = sqlx::query!(
+        r#"SELECT t.name, t.format, t.size, t.status, t.starts_at, w.username AS "winner?"
+        FROM tournaments t
+        LEFT JOIN users w ON w.id = t.winner_id
+        WHERE t.id = $1;"#,
+        tournament_id
+    )
+    .fetch_optional(&pool)
+    .await?
+    .ok_or(TournamentError::NotFound)?;
+
+    let players = sqlx::query!(
+        "SELECT u.id, u.username, tp.seed, tp.rating
+        FROM tournament_players tp
+        JOIN users u ON u.id = tp.user_id
+        WHERE tp.tournament_id = $1
+        ORDER BY tp.seed NULLS LAST, tp.registered_at;",
+        tournament_id
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    let matches = sqlx::query!(
+        r#"SELECT tm.round, tm.status, tm.player1_id, tm.player2_id,
+            p1.username AS "player1?", p2.username AS "player2?", w.username AS "winner?",
+            g.id AS "game_id?"
+        FROM tournament_matches tm
+        LEFT JOIN users p1 ON p1.id = tm.player1_id
+        LEFT JOIN users p2 ON p2.id = tm.player2_id
+        LEFT JOIN users w ON w.id = tm.winner_id
+        LEFT JOIN games g ON g.match_id = tm.match_id
+        WHERE tm.tournament_id = $1
+        ORDER BY tm.round, tm.position;"#,
+        tournament_id
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    let format = tournament
+        .format
+        .parse::<TournamentFormat>()
+        .unwrap_or(TournamentFormat::SingleElimination);
+    let total_rounds = matches.iter().map(|m| m.round).max().unwrap_or(0);
+    let mut rounds: Vec<BracketRoundView> = Vec::new();
+    for m in matches {
+        if rounds.last().map(|r| r.number) != Some(m.round) {
+            rounds.push(BracketRoundView {
+                number: m.round,
+                name: round_name(format, m.round, total_rounds),
+                matches: Vec::new(),
+            });
+        }
+        let is_yours = m.player1_id == Some(player_id) || m.player2_id == Some(player_id);
+        rounds.last_mut().unwrap().matches.push(BracketMatchView {
+            playable: is_yours && m.status == "in_progress",
+            player1: m.player1,
+            player2: m.player2,
+            winner: m.winner,
+            status: m.status,
+            game_id: m.game_id,
+        });
+    }
+
+    // A bracket speaks for itself, the other formats are decided by the standings
+    let standings = match format {
+        TournamentFormat::SingleElimination => Vec::new(),
+        _ if rounds.is_empty() => Vec::new(),
+        _ => load_standings(&mut *pool.acquire().await?, tournament_id).await?,
+    };
+
+    let registered = players.len() as i64;
+    let is_registered = players.iter().any(|p| p.id == player_id);
+    return Ok(TournamentView {
+        id: tournament_id,
+        name: tournament.name,
+        format: format.display_name().to_string(),
+        size: tournament.size,
+        registered,
+        can_register: tournament.status == "registering"
+            && !is_registered
+            && registered < tournament.size as i64,
+        status: tournament.status,
+        starts_at: format_time(tournament.starts_at),
+        winner: tournament.winner,
+        players: players
+            .into_iter()
+            .map(|p| TournamentPlayerView {
+                username: p.username,
+                seed: p.seed,
+                rating: p.rating.map(|r| r.round() as i64),
+            })
+            .collect(),
+        rounds,
+        standings,
+        is_registered,
+    });
+}
+
+fn round_name(format: TournamentFormat, round: i32, total_rounds: i32) -> String {
+    match (format, total_rounds - round) {
+        (TournamentFormat::SingleElimination, 0) => "Final".to_string(),
+        (TournamentFormat::SingleElimination, 1) => "Semifinals".to_string(),
+        (TournamentFormat::SingleElimination, 2) => "Quarterfinals".to_string(),
+        _ => format!("Round {}", round),
+    }
+}
+
+fn format_name(format: &str) -> String {
+    return format
+        .parse::<TournamentFormat>()
+        .map(|f| f.display_name().to_string())
+        .unwrap_or_else(|_| format.to_string());
+}
+
+fn format_time(time: DateTime<Utc>) -> String {
+    return time.format("%Y-%m-%d %H:%M UTC").to_string();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn players(count: u128) -> Vec<Uuid> {
+        return (1..=count).map(Uuid::from_u128).collect();
+    }
+
+    fn player(id: Uuid, seed: Option<i32>) -> StandingsPlayer {
+        return StandingsPlayer {
+            user_id: id,
+            username: id.to_string(),
+            seed,
+        };
+    }
+
+    fn finished(player1: Uuid, player2: Uuid, winner: Uuid) -> StandingsMatch {
+        return StandingsMatch {
+            player1_id: Some(player1),
+            player2_id: Some(player2),
+            winner_id: Some(winner),
+            status: "finished".to_string(),
+        };
+    }
+
+    #[test]
+    fn seeds_meet_as_late_as_possible() {
+        assert_eq!(seed_order(2), vec![1, 2]);
+        assert_eq!(seed_order(4), vec![1, 4, 2, 3]);
+        assert_eq!(seed_order(8), vec![1, 8, 4, 5, 2, 7, 3, 6]);
+    }
+
+    #[test]
+    fn bracket_pads_with_byes_for_the_top_seeds() {
+        let seeded = players(5);
+        assert_eq!(
+            bracket_pairings(&seeded),
+            vec![
+                (Some(seeded[0]), None),
+                (Some(seeded[3]), Some(seeded[4])),
+                (Some(seeded[1]), None),
+                (Some(seeded[2]), None),
+            ]
+        );
+
+        for count in 2..=MAX_TOURNAMENT_SIZE as u128 {
+            let seeded = players(count);
+            let pairings = bracket_pairings(&seeded);
+            let size = seeded.len().next_power_of_two();
+            assert_eq!(pairings.len(), size / 2, "{} players", count);
+
+            let mut byes: Vec<Uuid> = pairings
+                .iter()
+                .filter_map(|pairing| match pairing {
+                    (Some(_), Some(_)) => None,
+                    (Some(player), None) | (None, Some(player)) => Some(*player),
+                    (None, None) => panic!("empty match with {} players", count),
+                })
+                .collect();
+            byes.sort();
+            assert_eq!(byes, seeded[..size - seeded.len()], "{} players", count);
+        }
+    }
+
+    #[test]
+    fn swiss_pairs_down_the_standings() {
+        let ranked = players(4);
+        let (pairs, bye) = swiss_pairings(&ranked, &HashSet::new(), &HashSet::new());
+        assert_eq!(pairs, vec![(ranked[0], ranked[1]), (ranked[2], ranked[3])]);
+        assert_eq!(bye, None);
+    }
+
+    #[test]
+    fn swiss_backtracks_to_avoid_rematches() {
+        let ranked = players(4);
+        // Pairing 1 with 3 would leave 2 and 4, who already played, so 1 has to take 4
+        let played = HashSet::from([(ranked[0], ranked[1]), (ranked[3], ranked[1])]);
+        let (pairs, _) = swiss_pairings(&ranked, &played, &HashSet::new());
+        assert_eq!(pairs, vec![(ranked[0], ranked[3]), (ranked[1], ranked[2])]);
+    }
+
+    #[test]
+    fn swiss_allows_rematches_when_there_is_no_way_around_them() {
+        let ranked = players(4);
+        let played: HashSet<(Uuid, Uuid)> = ranked
+            .iter()
+            .flat_map(|&a| ranked.iter().map(move |&b| (a, b)))
+            .filter(|(a, b)| a != b)
+            .collect();
+        let have_played = |a: Uuid, b: Uuid| played.contains(&(a, b));
+        assert_eq!(pair_without_rematches(&ranked, &have_played), None);
+
+        let (pairs, _) = swiss_pairings(&ranked, &played, &HashSet::new());
+        assert_eq!(pairs, vec![(ranked[0], ranked[1]), (ranked[2], ranked[3])]);
+    }
+
+    #[test]
+    fn swiss_gives_the_bye_to_the_lowest_player_without_one() {
+        let ranked = players(5);
+        let (pairs, bye) = swiss_pairings(&ranked, &HashSet::new(), &HashSet::new());
+        assert_eq!(bye, Some(ranked[4]));
+        assert_eq!(pairs, vec![(ranked[0], ranked[1]), (ranked[2], ranked[3])]);
+
+        let had_bye = HashSet::from([ranked[4], ranked[3]]);
+        let (pairs, bye) = swiss_pairings(&ranked, &HashSet::new(), &had_bye);
+        assert_eq!(bye, Some(ranked[2]));
+        assert_eq!(pairs, vec![(ranked[0], ranked[1]), (ranked[3], ranked[4])]);
+    }
+
+    #[test]
+    fn round_robin_pairs_everyone_exactly_once() {
+        for count in 2..=9 {
+            let entrants = players(count);
+            let rounds = round_robin_rounds(&entrants);
+            let expected_rounds = if count % 2 == 0 { count - 1 } else { count };
+            assert_eq!(rounds.len() as u128, expected_rounds, "{} players", count);
+
+            let mut meetings: HashMap<(Uuid, Uuid), i32> = HashMap::new();
+            let mut sat_out: HashMap<Uuid, i32> = HashMap::new();
+            for round in &rounds {
+                let mut seen = HashSet::new();
+                for &(player1, player2) in round {
+                    match (player1, player2) {
+                        (Some(a), Some(b)) => {
+                            assert!(seen.insert(a) && seen.insert(b), "{} players", count);
+                            *meetings.entry((a.min(b), a.max(b))).or_default() += 1;
+                        }
+                        (Some(p), None) | (None, Some(p)) => {
+                            assert!(seen.insert(p), "{} players", count);
+                            *sat_out.entry(p).or_default() += 1;
+                        }
+                        (None, None) => panic!("empty match with {} players", count),
+                    }
+                }
+                assert_eq!(seen.len() as u128, count, "{} players", count);
+            }
+
+            let pairs = count * (count - 1) / 2;
+            assert_eq!(meetings.len() as u128, pairs, "{} players", count);
+            assert!(meetings.values().all(|&n| n == 1), "{} players", count);
+            if count % 2 == 1 {
+                assert_eq!(sat_out.len() as u128, count, "{} players", count);
+                assert!(sat_out.values().all(|&n| n == 1), "{} players", count);
+            } else {
+                assert!(sat_out.is_empty(), "{} players", count);
+            }
+        }
+    }
+
+    #[test]
+    fn standings_rank_by_points_then_buchholz_then_seed() {
+        let ids = players(4);
+        let (a, b, c, d) = (ids[0], ids[1], ids[2], ids[3]);
+        let entrants = vec![
+            player(a, Some(1)),
+            player(b, Some(2)),
+            player(c, Some(3)),
+            player(d, Some(4)),
+        ];
+        let matches = vec![
+            finished(a, b, a),
+            finished(c, d, c),
+            finished(a, c, a),
+            finished(d, b, d),
+        ];
+        let standings = compute_standings(entrants, &matches);
+
+        // c and d both have a point, c's opponents scored more
+        let order: Vec<Uuid> = standings.iter().map(|s| s.player_id).collect();
+        assert_eq!(order, vec![a, c, d, b]);
+        let summary: Vec<(usize, i32, i32)> = standings
+            .iter()
+            .map(|s| (s.rank, s.points, s.buchholz))
+            .collect();
+        assert_eq!(summary, vec![(1, 2, 1), (2, 1, 3), (3, 1, 1), (4, 0, 3)]);
+    }
+
+    #[test]
+    fn standings_fall_back_to_seed_and_count_awarded_byes() {
+        let ids = players(3);
+        let entrants = vec![
+            player(ids[0], None),
+            player(ids[1], Some(2)),
+            player(ids[2], Some(1)),
+        ];
+        let standings = compute_standings(entrants, &[]);
+        let order: Vec<Uuid> = standings.iter().map(|s| s.player_id).collect();
+        assert_eq!(order, vec![ids[2], ids[1], ids[0]]);
+
+        let bye = StandingsMatch {
+            player1_id: Some(ids[0]),
+            player2_id: None,
+            winner_id: Some(ids[0]),
+            status: "bye".to_string(),
+        };
+        let standings = compute_standings(vec![player(ids[0], None)], &[bye]);
+        assert_eq!((standings[0].points, standings[0].byes), (1, 1));
+    }
+}
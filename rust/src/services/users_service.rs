@@ -1,118 +1,86 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
 use axum::http::StatusCode;
 use jsonwebtoken as jwt;
 use sqlx::{Pool, Postgres};
 use uuid::Uuid;
 
+use super::{
+    matchmaking_service::GameType,
+    rating_service,
+    role_service::{self, Role},
+    session_service::{self, NewSession},
+};
+
+// Users seen within this window count as online
+const ONLINE_WINDOW_SECONDS: f64 = 300.0;
+// How often the users seen since the last write get their last_seen_at bumped
+const LAST_SEEN_FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+const DEFAULT_ACCESS_TOKEN_MINUTES: i64 = 15;
+
 #[derive(serde::Deserialize)]
 pub struct NewUserRequest {
     username: String,
     password: String,
-    email: String,
+    pub email: String,
 }
 
 #[derive(serde::Deserialize, serde::Serialize)]
 pub struct SignUpResponse {
     pub message: String,
     pub token: Option<String>,
+    pub refresh_token: Option<String>,
 }
 
 pub async fn sign_up_user(
     pool: Pool<Postgres>,
     body: NewUserRequest,
+    user_agent: Option<&str>,
 ) -> (StatusCode, SignUpResponse) {
     let hashed_password = bcrypt::hash(body.password.clone(), 12).unwrap();
-    let res = sqlx::query!(
-        "INSERT INTO users (username, password, email) VALUES ($1, $2, $3);",
+    let res = sqlx::query_scalar!(
+        r#"WITH new_user AS (
+            INSERT INTO users (username, password, email) VALUES ($1, $2, $3) RETURNING id
+        )
+        INSERT INTO user_roles (user_id, role) SELECT id, 'user' FROM new_user
+        RETURNING user_id AS "id!";"#,
         &body.username,
         hashed_password,
         &body.email
     )
-    .execute(&pool)
+    .fetch_one(&pool)
     .await;
 
     match res {
-        Ok(_) => {
-            let res = sqlx::query!(
-                "SELECT id, username, email, password FROM users WHERE email = $1",
-                &body.email,
-            )
-            .fetch_one(&pool)
-            .await;
-
-            match res {
-                Ok(user) => {
-                    if bcrypt::verify(body.password, &user.password).unwrap() {
-                        // Set expiration time to 1 year
-                        let claims = Claims {
-                            sub: 0,
-                            username: user.username.clone(),
-                            email: user.email,
-                            elo: 1000,
-                            id: user.id.to_string(),
-                            exp: (chrono::Utc::now() + chrono::Duration::days(365)).timestamp()
-                                as usize,
-                        };
-                        match jwt::encode(
-                            &jwt::Header::default(),
-                            &claims,
-                            &jwt::EncodingKey::from_secret(
-                                dotenv::var("SECRET").unwrap().as_bytes(),
-                            ),
-                        ) {
-                            Ok(t) => {
-                                return (
-                                    StatusCode::OK,
-                                    SignUpResponse {
-                                        message: format!("Welcome {}", user.username),
-                                        token: Some(t),
-                                    },
-                                )
-                            }
-                            Err(e) => {
-                                tracing::error!("error creating jwt {}", e.to_string());
-                                return (
-                                        StatusCode::INTERNAL_SERVER_ERROR,
-                                        SignUpResponse {
-                                            message: "There was an error. But your account was created. Please try logging in again later..."
-                                                .to_string(),
-                                            token: None,
-                                        },
-                                    );
-                            }
-                        };
-                    } else {
-                        return (
-                            StatusCode::UNAUTHORIZED,
-                            SignUpResponse {
-                                message: "Invalid username or password".to_string(),
-                                token: Some("".to_string()),
-                            },
-                        );
-                    }
-                }
-                Err(e) => match e {
-                    sqlx::Error::RowNotFound => {
-                        return (
-                            StatusCode::UNAUTHORIZED,
-                            SignUpResponse {
-                                message: "Invalid username or password".to_string(),
-                                token: None,
-                            },
-                        )
-                    }
-                    _ => {
-                        return (
-                            StatusCode::INTERNAL_SERVER_ERROR,
-                            SignUpResponse {
-                                message: "There was an error logging in. Please try again later..."
-                                    .to_string(),
-                                token: None,
-                            },
-                        )
-                    }
-                },
+        Ok(user_id) => match start_session(&pool, user_id, user_agent).await {
+            Ok((token, session)) => {
+                return (
+                    StatusCode::OK,
+                    SignUpResponse {
+                        message: format!("Welcome {}", body.username),
+                        token: Some(token),
+                        refresh_token: Some(session.refresh_token),
+                    },
+                )
             }
-        }
+            Err(e) => {
+                tracing::error!("error starting session {}", e.to_string());
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    SignUpResponse {
+                        message: "There was an error. But your account was created. Please try logging in again later..."
+                            .to_string(),
+                        token: None,
+                        refresh_token: None,
+                    },
+                );
+            }
+        },
         Err(e) => match e {
             sqlx::Error::Database(ref db_err) => {
                 if db_err.is_unique_violation() {
@@ -121,6 +89,7 @@ pub async fn sign_up_user(
                         SignUpResponse {
                             message: "Email or username already exists".to_string(),
                             token: None,
+                            refresh_token: None,
                         },
                     );
                 }
@@ -131,6 +100,7 @@ pub async fn sign_up_user(
                         message: "There was an error signing up. Please try again later..."
                             .to_string(),
                         token: None,
+                        refresh_token: None,
                     },
                 );
             }
@@ -142,6 +112,7 @@ pub async fn sign_up_user(
                         message: "There was an error signing up. Please try again later..."
                             .to_string(),
                         token: None,
+                        refresh_token: None,
                     },
                 );
             }
@@ -161,17 +132,33 @@ pub struct Claims {
     pub username: String,
     pub elo: usize,
     pub id: String,
-    email: String,
+    // Empty for guests, who haven't given one
+    pub email: String,
+    // The server-side session the token was issued for, see session_service
+    pub session: String,
+    // Loaded when the token is issued, so changes apply once it's refreshed
+    pub roles: Vec<Role>,
     exp: usize,
 }
 
+impl Claims {
+    pub fn has_role(&self, role: Role) -> bool {
+        return self.roles.iter().any(|held| held.grants(role));
+    }
+}
+
 #[derive(serde::Deserialize, serde::Serialize)]
 pub struct LoginResponse {
     pub message: String,
     pub token: String,
+    pub refresh_token: String,
 }
 
-pub async fn log_in_user(pool: Pool<Postgres>, body: LoginRequest) -> (StatusCode, LoginResponse) {
+pub async fn log_in_user(
+    pool: Pool<Postgres>,
+    body: LoginRequest,
+    user_agent: Option<&str>,
+) -> (StatusCode, LoginResponse) {
     let res = sqlx::query!(
         "SELECT id, username, email, password FROM users WHERE (username = $1 or email = $1);",
         &body.username_or_email,
@@ -181,48 +168,42 @@ pub async fn log_in_user(pool: Pool<Postgres>, body: LoginRequest) -> (StatusCod
 
     match res {
         Ok(user) => {
-            if bcrypt::verify(body.password, &user.password).unwrap() {
-                // Expiration time is 1 year from now
-                let claims = Claims {
-                    sub: 0,
-                    username: user.username.clone(),
-                    id: user.id.to_string(),
-                    elo: 1000,
-                    email: user.email,
-                    exp: (chrono::Utc::now() + chrono::Duration::days(365)).timestamp() as usize,
-                };
-                let token = match jwt::encode(
-                    &jwt::Header::default(),
-                    &claims,
-                    &jwt::EncodingKey::from_secret(dotenv::var("SECRET").unwrap().as_bytes()),
-                ) {
-                    Ok(t) => t,
+            // Players who only sign in through an OIDC provider have no password
+            let valid_password = user
+                .password
+                .is_some_and(|password| bcrypt::verify(body.password, &password).unwrap());
+            if valid_password {
+                match start_session(&pool, user.id, user_agent).await {
+                    Ok((token, session)) => {
+                        return (
+                            StatusCode::OK,
+                            LoginResponse {
+                                message: format!("Welcome {}", user.username),
+                                token,
+                                refresh_token: session.refresh_token,
+                            },
+                        );
+                    }
                     Err(e) => {
-                        tracing::error!("error creating jwt {}", e.to_string());
+                        tracing::error!("error starting session {}", e.to_string());
                         return (
                             StatusCode::INTERNAL_SERVER_ERROR,
                             LoginResponse {
                                 message: "There was an error logging in. Please try again later..."
                                     .to_string(),
                                 token: "".to_string(),
+                                refresh_token: "".to_string(),
                             },
                         );
                     }
-                };
-
-                return (
-                    StatusCode::OK,
-                    LoginResponse {
-                        message: format!("Welcome {}", user.username),
-                        token,
-                    },
-                );
+                }
             } else {
                 return (
                     StatusCode::UNAUTHORIZED,
                     LoginResponse {
                         message: "Invalid username or password".to_string(),
                         token: "".to_string(),
+                        refresh_token: "".to_string(),
                     },
                 );
             }
@@ -234,6 +215,7 @@ pub async fn log_in_user(pool: Pool<Postgres>, body: LoginRequest) -> (StatusCod
                     LoginResponse {
                         message: "Invalid username or password".to_string(),
                         token: "".to_string(),
+                        refresh_token: "".to_string(),
                     },
                 )
             }
@@ -244,9 +226,126 @@ pub async fn log_in_user(pool: Pool<Postgres>, body: LoginRequest) -> (StatusCod
                         message: "There was an error logging in. Please try again later..."
                             .to_string(),
                         token: "".to_string(),
+                        refresh_token: "".to_string(),
                     },
                 )
             }
         },
     };
 }
+
+#[derive(Debug)]
+pub enum AccessTokenError {
+    Database(sqlx::Error),
+    Jwt(jwt::errors::Error),
+}
+
+impl fmt::Display for AccessTokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccessTokenError::Database(e) => write!(f, "Database error: {}", e),
+            AccessTokenError::Jwt(e) => write!(f, "Error creating jwt: {}", e),
+        }
+    }
+}
+
+impl From<sqlx::Error> for AccessTokenError {
+    fn from(e: sqlx::Error) -> Self {
+        return AccessTokenError::Database(e);
+    }
+}
+
+// Starts a session and signs the first access token for it
+pub async fn start_session(
+    pool: &Pool<Postgres>,
+    user_id: Uuid,
+    user_agent: Option<&str>,
+) -> Result<(String, NewSession), AccessTokenError> {
+    let session = session_service::create_session(pool, user_id, user_agent).await?;
+    let token = issue_access_token(pool, user_id, session.id).await?;
+    return Ok((token, session));
+}
+
+// Signs an access token for the session. They only last ACCESS_TOKEN_MINUTES, after which
+// auth_middleware swaps the refresh token for a new one.
+pub async fn issue_access_token(
+    pool: &Pool<Postgres>,
+    user_id: Uuid,
+    session_id: Uuid,
+) -> Result<String, AccessTokenError> {
+    let user = sqlx::query!("SELECT username, email FROM users WHERE id = $1;", user_id)
+        .fetch_one(pool)
+        .await?;
+    let elo = rating_service::get_rating(pool, user_id, GameType::Ranked).await?;
+    let roles = role_service::get_roles(pool, user_id).await?;
+    let minutes = dotenv::var("ACCESS_TOKEN_MINUTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_ACCESS_TOKEN_MINUTES);
+
+    let claims = Claims {
+        sub: 0,
+        username: user.username,
+        id: user_id.to_string(),
+        elo: elo.round() as usize,
+        email: user.email.unwrap_or_default(),
+        session: session_id.to_string(),
+        roles,
+        exp: (chrono::Utc::now() + chrono::Duration::minutes(minutes)).timestamp() as usize,
+    };
+    return jwt::encode(
+        &jwt::Header::default(),
+        &claims,
+        &jwt::EncodingKey::from_secret(dotenv::var("SECRET").unwrap().as_bytes()),
+    )
+    .map_err(AccessTokenError::Jwt);
+}
+
+// Users who made requests since the last write. Requests only add to this in memory,
+// `run_last_seen_writer` saves the whole batch in one UPDATE.
+#[derive(Default)]
+pub struct ActivityTracker {
+    seen: Mutex<HashSet<Uuid>>,
+}
+
+impl ActivityTracker {
+    // Records that the user is active
+    pub fn touch(&self, user_id: Uuid) {
+        self.seen.lock().unwrap().insert(user_id);
+    }
+
+    fn take(&self) -> Vec<Uuid> {
+        return self.seen.lock().unwrap().drain().collect();
+    }
+}
+
+// Runs forever, writing who has been active to users.last_seen_at
+pub async fn run_last_seen_writer(pool: Pool<Postgres>, activity: Arc<ActivityTracker>) {
+    let mut interval = tokio::time::interval(LAST_SEEN_FLUSH_INTERVAL);
+    loop {
+        interval.tick().await;
+        let seen = activity.take();
+        if seen.is_empty() {
+            continue;
+        }
+        let updated = sqlx::query!(
+            "UPDATE users SET last_seen_at = NOW() WHERE id = ANY($1);",
+            &seen
+        )
+        .execute(&pool)
+        .await;
+        if let Err(e) = updated {
+            tracing::error!("error updating last seen {}", e.to_string());
+        }
+    }
+}
+
+pub async fn count_online_users(pool: Pool<Postgres>) -> Result<i64, sqlx::Error> {
+    return sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "count!" FROM users
+        WHERE last_seen_at > NOW() - make_interval(secs => $1);"#,
+        ONLINE_WINDOW_SECONDS,
+    )
+    .fetch_one(&pool)
+    .await;
+}